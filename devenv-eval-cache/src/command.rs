@@ -19,16 +19,24 @@ pub enum CommandError {
     Io(#[from] io::Error),
     #[error(transparent)]
     Sqlx(#[from] sqlx::Error),
-    #[error("Nix command failed: {0}")]
-    NonZeroExitStatus(process::ExitStatus),
+    #[error("Nix command failed: {status}")]
+    NonZeroExitStatus {
+        status: process::ExitStatus,
+        /// The messages the command logged, as they would have been printed.
+        stderr: Vec<u8>,
+    },
 }
 
+type OnStderr = Box<dyn Fn(&InternalLog) + Send>;
+
 pub struct CachedCommand<'a> {
     pool: &'a sqlx::SqlitePool,
     force_refresh: bool,
+    read_only: bool,
+    salt: String,
     extra_paths: Vec<PathBuf>,
     excluded_paths: Vec<PathBuf>,
-    on_stderr: Option<Box<dyn Fn(&InternalLog) + Send>>,
+    on_stderr: Option<OnStderr>,
 }
 
 impl<'a> CachedCommand<'a> {
@@ -36,6 +44,8 @@ impl<'a> CachedCommand<'a> {
         Self {
             pool,
             force_refresh: false,
+            read_only: false,
+            salt: String::new(),
             extra_paths: Vec::new(),
             excluded_paths: Vec::new(),
             on_stderr: None,
@@ -60,6 +70,18 @@ impl<'a> CachedCommand<'a> {
         self
     }
 
+    /// Read from the cache, but never write to it.
+    pub fn read_only(&mut self) -> &mut Self {
+        self.read_only = true;
+        self
+    }
+
+    /// Mix `salt` into the cache key, so output cached under a different salt is never reused.
+    pub fn salt<S: Into<String>>(&mut self, salt: S) -> &mut Self {
+        self.salt = salt.into();
+        self
+    }
+
     pub fn on_stderr<F>(&mut self, f: F) -> &mut Self
     where
         F: Fn(&InternalLog) + Send + 'static,
@@ -74,11 +96,13 @@ impl<'a> CachedCommand<'a> {
     /// the cached output will be returned.
     pub async fn output(mut self, cmd: &'a mut Command) -> Result<Output, CommandError> {
         let raw_cmd = format!("{:?}", cmd);
-        let cmd_hash = hash::digest(&raw_cmd);
+        let cmd_hash = hash::digest(&format!("{}{}", self.salt, raw_cmd));
 
         // Check whether the command has been previously run and the files it depends on have not been changed.
         if !self.force_refresh {
-            if let Ok(Some(output)) = query_cached_output(self.pool, &cmd_hash).await {
+            if let Ok(Some(output)) =
+                query_cached_output(self.pool, &cmd_hash, self.read_only).await
+            {
                 return Ok(output);
             }
         }
@@ -111,6 +135,7 @@ impl<'a> CachedCommand<'a> {
                     // FIX: verbosity
                     if let Some(msg) = log.get_log_msg_by_level(Verbosity::Info) {
                         raw_lines.extend_from_slice(msg.as_bytes());
+                        raw_lines.push(b'\n');
                     }
 
                     if let Some(op) = extract_op_from_log_line(log) {
@@ -129,13 +154,13 @@ impl<'a> CachedCommand<'a> {
 
         let status = child.wait().map_err(CommandError::Io)?;
 
-        if !status.success() {
-            return Err(CommandError::NonZeroExitStatus(status));
-        }
-
         let stdout = stdout_thread.await.unwrap().map_err(CommandError::Io)?;
         let (mut ops, stderr) = stderr_thread.await.unwrap();
 
+        if !status.success() {
+            return Err(CommandError::NonZeroExitStatus { status, stderr });
+        }
+
         // Remove excluded paths if any are a parent directory
         ops.retain_mut(|op| {
             !self
@@ -177,16 +202,18 @@ impl<'a> CachedCommand<'a> {
                 .collect::<String>(),
         );
 
-        let _ = db::insert_command_with_files(
-            self.pool,
-            &raw_cmd,
-            &cmd_hash,
-            &input_hash,
-            &stdout,
-            &file_paths,
-        )
-        .await
-        .map_err(CommandError::Sqlx)?;
+        if !self.read_only {
+            let _ = db::insert_command_with_files(
+                self.pool,
+                &raw_cmd,
+                &cmd_hash,
+                &input_hash,
+                &stdout,
+                &file_paths,
+            )
+            .await
+            .map_err(CommandError::Sqlx)?;
+        }
 
         Ok(Output {
             status,
@@ -259,9 +286,11 @@ impl From<db::FilePathRow> for FilePath {
 ///
 /// Returns the cached output if the command has been cached and none of the file dependencies have
 /// been updated.
+/// In `read_only` mode, timestamps of cache hits and touched files are not updated.
 async fn query_cached_output(
     pool: &SqlitePool,
     cmd_hash: &str,
+    read_only: bool,
 ) -> Result<Option<Output>, CommandError> {
     let cached_cmd = db::get_command_by_hash(pool, cmd_hash)
         .await
@@ -302,7 +331,7 @@ async fn query_cached_output(
                     match file_state {
                         FileState::MetadataModified {
                             modified_at, path, ..
-                        } => {
+                        } if !read_only => {
                             // TODO: batch with query builder?
                             db::update_file_modified_at(pool, path, modified_at)
                                 .await
@@ -323,9 +352,11 @@ async fn query_cached_output(
         if should_refresh {
             Ok(None)
         } else {
-            db::update_command_updated_at(pool, cmd.id)
-                .await
-                .map_err(CommandError::Sqlx)?;
+            if !read_only {
+                db::update_command_updated_at(pool, cmd.id)
+                    .await
+                    .map_err(CommandError::Sqlx)?;
+            }
 
             // No files have been modified, returning cached output
             Ok(Some(Output {
@@ -428,7 +459,7 @@ fn check_file_state(file: db::FilePathRow) -> io::Result<FileState> {
 fn truncate_to_seconds(time: SystemTime) -> io::Result<SystemTime> {
     let duration_since_epoch = time
         .duration_since(UNIX_EPOCH)
-        .map_err(|_| io::Error::new(io::ErrorKind::Other, "SystemTime before UNIX EPOCH"))?;
+        .map_err(|_| io::Error::other("SystemTime before UNIX EPOCH"))?;
 
     let seconds = duration_since_epoch.as_secs();
     Ok(UNIX_EPOCH + std::time::Duration::from_secs(seconds))
@@ -521,4 +552,49 @@ mod test {
             Ok(FileState::Removed { .. })
         ));
     }
+
+    async fn count_cached_commands(pool: &SqlitePool) -> i64 {
+        sqlx::query_scalar("SELECT COUNT(*) FROM cached_cmd")
+            .fetch_one(pool)
+            .await
+            .unwrap()
+    }
+
+    #[sqlx::test]
+    async fn test_cache_hit_skips_command(pool: SqlitePool) {
+        let temp_dir = TempDir::new("test_cache_hit_skips_command").unwrap();
+        let runs = temp_dir.path().join("runs");
+        let run = |salt: &str| {
+            let mut cmd = Command::new("sh");
+            cmd.arg("-c")
+                .arg(format!("echo run >> {}; echo hello", runs.display()));
+            let mut cached_cmd = CachedCommand::new(&pool);
+            cached_cmd.salt(salt);
+            async move { cached_cmd.output(&mut cmd).await.unwrap() }
+        };
+        let count_runs = || std::fs::read_to_string(&runs).unwrap().lines().count();
+
+        assert_eq!(run("1.0").await.stdout, b"hello\n");
+        assert_eq!(run("1.0").await.stdout, b"hello\n");
+        assert_eq!(count_runs(), 1);
+
+        run("1.1").await;
+        assert_eq!(count_runs(), 2);
+    }
+
+    #[sqlx::test]
+    async fn test_read_only_cache_miss(pool: SqlitePool) {
+        let mut cmd = Command::new("echo");
+        cmd.arg("hello");
+        let mut cached_cmd = CachedCommand::new(&pool);
+        cached_cmd.read_only();
+        let output = cached_cmd.output(&mut cmd).await.unwrap();
+        assert!(output.status.success());
+        assert_eq!(count_cached_commands(&pool).await, 0);
+
+        let mut cmd = Command::new("echo");
+        cmd.arg("hello");
+        CachedCommand::new(&pool).output(&mut cmd).await.unwrap();
+        assert_eq!(count_cached_commands(&pool).await, 1);
+    }
 }
@@ -1,11 +1,14 @@
 use super::command::FilePath;
-use sqlx::sqlite::{Sqlite, SqliteConnectOptions, SqliteJournalMode, SqliteRow, SqliteSynchronous};
+use sqlx::sqlite::{
+    Sqlite, SqliteConnectOptions, SqliteConnection, SqliteJournalMode, SqliteRow, SqliteSynchronous,
+};
 use sqlx::{Acquire, Row, SqlitePool};
 use std::ffi::OsStr;
 use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::time::{SystemTime, UNIX_EPOCH};
+use thiserror::Error;
 
 pub async fn setup_db<P: AsRef<str>>(database_url: P) -> Result<SqlitePool, sqlx::Error> {
     let conn_options = SqliteConnectOptions::from_str(database_url.as_ref())?
@@ -24,6 +27,183 @@ pub async fn setup_db<P: AsRef<str>>(database_url: P) -> Result<SqlitePool, sqlx
     Ok(pool)
 }
 
+/// Like [`setup_db`], but starts over with an empty database at `path`
+/// if the existing one is corrupted or its migrations don't match ours.
+///
+/// Other errors, like the database being locked by another process, are returned as is.
+pub async fn setup_db_or_recreate<P: AsRef<Path>>(path: P) -> Result<SqlitePool, sqlx::Error> {
+    let path = path.as_ref();
+    let database_url = format!("sqlite:{}", path.to_string_lossy());
+    match setup_db(&database_url).await {
+        Ok(pool) => Ok(pool),
+        Err(e) if !is_corrupted(&e) => Err(e),
+        Err(_) => {
+            for suffix in ["", "-wal", "-shm"] {
+                let mut file = path.as_os_str().to_owned();
+                file.push(suffix);
+                let _ = std::fs::remove_file(file);
+            }
+            setup_db(&database_url).await
+        }
+    }
+}
+
+/// Whether `error` means the database can't be used as is and is safe to throw away.
+fn is_corrupted(error: &sqlx::Error) -> bool {
+    use sqlx::migrate::MigrateError;
+
+    const SQLITE_CORRUPT: i32 = 11;
+    const SQLITE_NOTADB: i32 = 26;
+
+    match error {
+        sqlx::Error::Database(e) => e
+            .code()
+            .and_then(|code| code.parse::<i32>().ok())
+            // Extended result codes keep the primary code in the lowest byte
+            .is_some_and(|code| matches!(code & 0xff, SQLITE_CORRUPT | SQLITE_NOTADB)),
+        sqlx::Error::Migrate(e) => match e.as_ref() {
+            MigrateError::VersionMismatch(_) | MigrateError::VersionMissing(_) => true,
+            MigrateError::Execute(e) | MigrateError::ExecuteMigration(e, _) => is_corrupted(e),
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// Open an existing database without writing to it.
+///
+/// Migrations are not run, so the database must have been set up with [`setup_db`] beforehand.
+pub async fn setup_db_read_only<P: AsRef<str>>(database_url: P) -> Result<SqlitePool, sqlx::Error> {
+    let conn_options = SqliteConnectOptions::from_str(database_url.as_ref())?
+        .foreign_keys(true)
+        .read_only(true);
+
+    SqlitePool::connect_with(conn_options).await
+}
+
+#[derive(Error, Debug)]
+pub enum ImportError {
+    #[error("{0} is not an exported eval cache")]
+    NotAnExport(PathBuf),
+    #[error("the eval cache was exported by devenv {found}, but this is devenv {expected}")]
+    VersionMismatch { expected: String, found: String },
+    #[error(transparent)]
+    Sqlx(#[from] sqlx::Error),
+}
+
+/// Write a standalone copy of the database to `path`, tagged with the devenv `version`.
+pub async fn export_db(pool: &SqlitePool, path: &Path, version: &str) -> Result<(), sqlx::Error> {
+    sqlx::query("VACUUM INTO ?")
+        .bind(path.to_string_lossy())
+        .execute(pool)
+        .await?;
+
+    let export = SqlitePool::connect_with(SqliteConnectOptions::new().filename(path)).await?;
+    sqlx::query("CREATE TABLE export_meta (version TEXT NOT NULL)")
+        .execute(&export)
+        .await?;
+    sqlx::query("INSERT INTO export_meta (version) VALUES (?)")
+        .bind(version)
+        .execute(&export)
+        .await?;
+    export.close().await;
+
+    Ok(())
+}
+
+/// Merge a database written by [`export_db`] into this one.
+///
+/// The export must come from the same devenv `version`. Returns the number of imported commands.
+pub async fn import_db(pool: &SqlitePool, path: &Path, version: &str) -> Result<u64, ImportError> {
+    let export =
+        SqlitePool::connect_with(SqliteConnectOptions::new().filename(path).read_only(true))
+            .await?;
+    let found: Option<String> = sqlx::query_scalar("SELECT version FROM export_meta")
+        .fetch_optional(&export)
+        .await
+        .map_err(|_| ImportError::NotAnExport(path.to_path_buf()))?;
+    export.close().await;
+    let found = found.ok_or_else(|| ImportError::NotAnExport(path.to_path_buf()))?;
+    if found != version {
+        return Err(ImportError::VersionMismatch {
+            expected: version.to_string(),
+            found,
+        });
+    }
+
+    // ATTACH is per connection, so hold on to a single one.
+    let mut conn = pool.acquire().await?;
+    sqlx::query("ATTACH DATABASE ? AS import")
+        .bind(path.to_string_lossy())
+        .execute(&mut *conn)
+        .await?;
+
+    // Detach before propagating any error, as the connection goes back to the pool.
+    let imported = merge_attached(&mut conn).await;
+    let detached = sqlx::query("DETACH DATABASE import")
+        .execute(&mut *conn)
+        .await;
+    if detached.is_err() {
+        // Close the connection rather than pool one that still has the import attached.
+        let _ = conn.detach();
+    }
+
+    let imported = imported?;
+    detached?;
+    Ok(imported)
+}
+
+/// Copy the commands and files of the database attached as `import` into this one.
+async fn merge_attached(conn: &mut SqliteConnection) -> Result<u64, sqlx::Error> {
+    let mut tx = conn.begin().await?;
+
+    // Replacing a command cascades to its old input paths.
+    let imported = sqlx::query(
+        r#"
+        INSERT OR REPLACE INTO cached_cmd (raw, cmd_hash, input_hash, output, updated_at)
+        SELECT raw, cmd_hash, input_hash, output, updated_at
+        FROM import.cached_cmd
+        "#,
+    )
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+
+    sqlx::query(
+        r#"
+        INSERT INTO file_path (path, is_directory, content_hash, modified_at, updated_at)
+        SELECT path, is_directory, content_hash, modified_at, updated_at
+        FROM import.file_path
+        WHERE true
+        ON CONFLICT (path) DO UPDATE
+        SET content_hash = excluded.content_hash,
+            is_directory = excluded.is_directory,
+            modified_at = excluded.modified_at,
+            updated_at = excluded.updated_at
+        "#,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    // Row ids differ between databases, so link commands and files by their natural keys.
+    sqlx::query(
+        r#"
+        INSERT OR IGNORE INTO cmd_input_path (cached_cmd_id, file_path_id)
+        SELECT cc.id, fp.id
+        FROM import.cmd_input_path icip
+        JOIN import.cached_cmd icc ON icc.id = icip.cached_cmd_id
+        JOIN import.file_path ifp ON ifp.id = icip.file_path_id
+        JOIN cached_cmd cc ON cc.cmd_hash = icc.cmd_hash
+        JOIN file_path fp ON fp.path = ifp.path
+        "#,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+    Ok(imported)
+}
+
 /// The row type for the `cached_cmd` table.
 #[derive(Clone, Debug)]
 pub struct CommandRow {
@@ -345,8 +525,36 @@ mod tests {
     use crate::hash;
 
     use super::*;
+    use sqlx::sqlite::SqlitePoolOptions;
     use sqlx::SqlitePool;
 
+    #[tokio::test]
+    async fn test_corrupted_db_is_recreated() {
+        let dir = tempdir::TempDir::new("test_corrupted_db_is_recreated").unwrap();
+        let path = dir.path().join("nix-eval-cache.db");
+        std::fs::write(&path, b"not a database, just some garbage").unwrap();
+
+        let pool = setup_db_or_recreate(&path).await.unwrap();
+        let commands: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM cached_cmd")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(commands, 0);
+    }
+
+    #[tokio::test]
+    async fn test_unopenable_db_is_kept() {
+        let dir = tempdir::TempDir::new("test_unopenable_db_is_kept").unwrap();
+        let path = dir.path().join("nix-eval-cache.db");
+        // A database that can't be opened, but isn't corrupted
+        std::fs::create_dir(&path).unwrap();
+        std::fs::write(dir.path().join("nix-eval-cache.db-wal"), b"wal").unwrap();
+
+        let error = setup_db_or_recreate(&path).await.unwrap_err();
+        assert!(!is_corrupted(&error));
+        assert!(dir.path().join("nix-eval-cache.db-wal").exists());
+    }
+
     #[sqlx::test]
     async fn test_insert_and_retrieve_command(pool: SqlitePool) {
         let raw_cmd = "nix-build -A hello";
@@ -604,4 +812,98 @@ mod tests {
         assert!(file_ids1.contains(&file_ids2[0])); // file2 is reused
         assert!(!file_ids1.contains(&file_ids2[1])); // file3 is new
     }
+
+    #[sqlx::test]
+    async fn test_export_and_import(pool: SqlitePool) {
+        let raw_cmd = "nix-build -A hello";
+        let cmd_hash = hash::digest(raw_cmd);
+        let paths = vec![FilePath {
+            path: "/path/to/file1".into(),
+            is_directory: false,
+            content_hash: "hash1".to_string(),
+            modified_at: SystemTime::now(),
+        }];
+        insert_command_with_files(&pool, raw_cmd, &cmd_hash, "input", b"Hello", &paths)
+            .await
+            .unwrap();
+
+        let dir = tempdir::TempDir::new("eval-cache-export").unwrap();
+        let export_path = dir.path().join("export.db");
+        export_db(&pool, &export_path, "1.0.0").await.unwrap();
+        assert!(export_path.exists());
+
+        let target_path = dir.path().join("target.db");
+        let target = setup_db(format!("sqlite:{}", target_path.display()))
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            import_db(&target, &export_path, "2.0.0").await,
+            Err(ImportError::VersionMismatch { found, .. }) if found == "1.0.0"
+        ));
+        assert!(get_command_by_hash(&target, &cmd_hash)
+            .await
+            .unwrap()
+            .is_none());
+
+        let imported = import_db(&target, &export_path, "1.0.0").await.unwrap();
+        assert_eq!(imported, 1);
+
+        let command = get_command_by_hash(&target, &cmd_hash)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(command.output, b"Hello");
+        let files = get_files_by_command_hash(&target, &cmd_hash).await.unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path, PathBuf::from("/path/to/file1"));
+        assert_eq!(files[0].content_hash, "hash1");
+
+        // The live database isn't an export
+        assert!(matches!(
+            import_db(&target, &target_path, "1.0.0").await,
+            Err(ImportError::NotAnExport(_))
+        ));
+    }
+
+    #[sqlx::test]
+    async fn test_failed_import_detaches(pool: SqlitePool) {
+        let dir = tempdir::TempDir::new("eval-cache-export").unwrap();
+        let export_path = dir.path().join("export.db");
+        export_db(&pool, &export_path, "1.0.0").await.unwrap();
+
+        // Tagged as an export, but without any of the tables to import.
+        let broken_path = dir.path().join("broken.db");
+        let broken = SqlitePool::connect_with(
+            SqliteConnectOptions::new()
+                .filename(&broken_path)
+                .create_if_missing(true),
+        )
+        .await
+        .unwrap();
+        sqlx::query("CREATE TABLE export_meta (version TEXT NOT NULL)")
+            .execute(&broken)
+            .await
+            .unwrap();
+        sqlx::query("INSERT INTO export_meta (version) VALUES ('1.0.0')")
+            .execute(&broken)
+            .await
+            .unwrap();
+        broken.close().await;
+
+        // A single connection, so the second import reuses the one the first attached on.
+        let target_url = format!("sqlite:{}", dir.path().join("target.db").display());
+        setup_db(&target_url).await.unwrap().close().await;
+        let target = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect(&target_url)
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            import_db(&target, &broken_path, "1.0.0").await,
+            Err(ImportError::Sqlx(_))
+        ));
+        assert_eq!(import_db(&target, &export_path, "1.0.0").await.unwrap(), 0);
+    }
 }
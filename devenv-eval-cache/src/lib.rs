@@ -5,4 +5,4 @@ pub mod internal_log;
 pub mod op;
 
 pub use command::{supports_eval_caching, CachedCommand, Output};
-pub use db::setup_db;
+pub use db::{export_db, import_db, setup_db, setup_db_read_only, ImportError};
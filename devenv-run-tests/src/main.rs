@@ -1,9 +1,9 @@
 use clap::Parser;
 use devenv::log::Level;
 use devenv::log::Logger;
-use devenv::{Devenv, DevenvOptions};
+use devenv::{BuildError, Devenv, DevenvOptions};
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
@@ -89,13 +89,12 @@ async fn run_tests_in_directory(
                 let tmpdir = tempdir::TempDir::new_in(path, ".devenv")
                     .expect("Failed to create temporary directory");
 
-                let options = DevenvOptions {
-                    config,
-                    devenv_root: Some(cwd.join(path)),
-                    devenv_dotfile: Some(tmpdir.path().to_path_buf()),
-                    ..Default::default()
-                };
-                let mut devenv = Devenv::new(options).await;
+                let options = DevenvOptions::builder()
+                    .config(config)
+                    .devenv_root(cwd.join(path))
+                    .devenv_dotfile(tmpdir.path())
+                    .build();
+                let mut devenv = Devenv::new(options).await?;
 
                 // A script to patch files in the working directory before the shell.
                 let patch_script = ".patch.sh";
@@ -120,15 +119,27 @@ async fn run_tests_in_directory(
                 if setup_script_path.exists() {
                     println!("    Running {setup_script}");
                     devenv
-                        .shell(&Some(format!("./{setup_script}")), &[], false)
+                        .shell(&Some(format!("./{setup_script}")), &[], &[], false)
                         .await?;
                 }
 
+                // Attributes to build before the test, one per line.
+                let build_file_path = path.join(".build-attributes");
+                let built = if build_file_path.exists() {
+                    println!("    Building .build-attributes");
+                    build_attributes(&mut devenv, &build_file_path).await
+                } else {
+                    Ok(())
+                };
+                if let Err(e) = &built {
+                    println!("    {e}");
+                }
+
                 // TODO: wait for processes to shut down before exiting
                 let status = devenv.test().await;
                 let result = TestResult {
                     name: dir_name.to_string(),
-                    passed: status.is_ok(),
+                    passed: built.is_ok() && status.is_ok(),
                 };
                 test_results.push(result);
             }
@@ -138,6 +149,43 @@ async fn run_tests_in_directory(
     Ok(test_results)
 }
 
+/// Build each attribute listed in `file` with `Devenv::build_attribute`.
+/// Attributes prefixed with `!` must fail with an error recognized from Nix's output.
+async fn build_attributes(devenv: &mut Devenv, file: &Path) -> Result<(), String> {
+    let attributes = fs::read_to_string(file).map_err(|e| e.to_string())?;
+    for line in attributes.lines().map(str::trim).filter(|l| !l.is_empty()) {
+        if let Some(attribute) = line.strip_prefix('!') {
+            match devenv.build_attribute(attribute).await {
+                Ok(path) => {
+                    return Err(format!(
+                        "Building {attribute} should have failed, got {}",
+                        path.display()
+                    ))
+                }
+                Err(e) => {
+                    if !matches!(e.downcast_ref::<BuildError>(), Some(BuildError::Nix { .. })) {
+                        return Err(format!(
+                            "Building {attribute} failed without a Nix error: {e:?}"
+                        ));
+                    }
+                }
+            }
+        } else {
+            let path = devenv
+                .build_attribute(line)
+                .await
+                .map_err(|e| format!("{e:?}"))?;
+            if !path.exists() {
+                return Err(format!(
+                    "Building {line} returned {}, which doesn't exist",
+                    path.display()
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = Args::parse();
@@ -0,0 +1,203 @@
+//! Hashing of the files and environment variables a task declares as its inputs,
+//! so that it can be skipped when none of them changed since its last successful run.
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::{fs, io};
+
+/// What is remembered about the last successful run of a task.
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct Fingerprint {
+    pub hash: String,
+    pub output: Option<serde_json::Value>,
+}
+
+impl Fingerprint {
+    pub fn load(path: &Path) -> Option<Self> {
+        let contents = fs::read_to_string(path).ok()?;
+        serde_json::from_str(&contents).ok()
+    }
+
+    pub fn store(&self, path: &Path) -> io::Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, serde_json::to_string(self)?)
+    }
+}
+
+/// Hash the task command together with the contents of every file matching `files`
+/// and the values of the `env` variables.
+///
+/// Variables set for the run in `run_env` take precedence over the process environment.
+pub(crate) fn hash(
+    command: &str,
+    files: &[String],
+    env: &[String],
+    run_env: &BTreeMap<String, String>,
+) -> io::Result<String> {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(command.as_bytes());
+
+    for pattern in files {
+        hasher.update(b"\0file-pattern\0");
+        hasher.update(pattern.as_bytes());
+        // The list of matches is part of the hash, so a deleted file is a change too
+        for path in expand_glob(pattern) {
+            hasher.update(b"\0file\0");
+            hasher.update(path.as_os_str().as_encoded_bytes());
+            hasher.update(b"\0");
+            hasher.update(&fs::read(&path)?);
+        }
+    }
+
+    for name in env {
+        hasher.update(b"\0env\0");
+        hasher.update(name.as_bytes());
+        let value = run_env
+            .get(name)
+            .cloned()
+            .or_else(|| std::env::var(name).ok());
+        match value {
+            Some(value) => {
+                hasher.update(b"=");
+                hasher.update(value.as_bytes());
+            }
+            None => {
+                hasher.update(b"\0unset");
+            }
+        }
+    }
+
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+/// List the files matching a glob, in a stable order.
+///
+/// Supports `*` and `?` within a path component and `**` for any number of directories.
+/// A pattern without wildcards matches the file itself, if it exists.
+/// Directories that can't be read are skipped.
+pub(crate) fn expand_glob(pattern: &str) -> Vec<PathBuf> {
+    let is_wildcard = |component: &str| component.contains(['*', '?']);
+
+    let mut base = PathBuf::new();
+    let mut components = pattern.split('/').peekable();
+    while let Some(component) = components.next_if(|c| !is_wildcard(c)) {
+        if component.is_empty() && base.as_os_str().is_empty() {
+            base.push("/");
+        } else {
+            base.push(component);
+        }
+    }
+    if components.peek().is_none() {
+        return if base.is_file() { vec![base] } else { vec![] };
+    }
+
+    let components = components.collect::<Vec<_>>();
+    // Without `**`, nothing deeper than the pattern itself can match
+    let max_depth = (!components.contains(&"**")).then_some(components.len());
+    let regex = glob_regex(&components.join("/"));
+    let walk_root = if base.as_os_str().is_empty() {
+        PathBuf::from(".")
+    } else {
+        base.clone()
+    };
+    let mut matches = Vec::new();
+    walk(&walk_root, 1, max_depth, &mut |path| {
+        let relative = path.strip_prefix(&walk_root).unwrap_or(path);
+        if relative
+            .to_str()
+            .is_some_and(|relative| regex.is_match(relative))
+        {
+            matches.push(base.join(relative));
+        }
+    });
+    matches.sort();
+    matches
+}
+
+fn glob_regex(glob: &str) -> Regex {
+    let mut regex = String::from("^");
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                if chars.peek() == Some(&'/') {
+                    chars.next();
+                    regex.push_str("(?:.*/)?");
+                } else {
+                    regex.push_str(".*");
+                }
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    Regex::new(&regex).expect("escaped glob is a valid regex")
+}
+
+/// Visit the files under `dir`, whose entries are at `depth`, down to `max_depth` if given.
+fn walk(dir: &Path, depth: usize, max_depth: Option<usize>, visit: &mut impl FnMut(&Path)) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        // Don't follow symlinked directories, they may form loops
+        if entry.file_type().is_ok_and(|file_type| file_type.is_dir()) {
+            if max_depth.is_none_or(|max_depth| depth < max_depth) {
+                walk(&path, depth + 1, max_depth, visit);
+            }
+        } else if path.is_file() {
+            visit(&path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand_glob() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir_all(root.join("src/nested")).unwrap();
+        fs::write(root.join("src/a.rs"), "").unwrap();
+        fs::write(root.join("src/b.txt"), "").unwrap();
+        fs::write(root.join("src/nested/c.rs"), "").unwrap();
+
+        let glob = |pattern: &str| {
+            expand_glob(&format!("{}/{}", root.display(), pattern))
+                .into_iter()
+                .map(|path| path.strip_prefix(root).unwrap().display().to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(glob("src/*.rs"), ["src/a.rs"]);
+        assert_eq!(glob("src/**/*.rs"), ["src/a.rs", "src/nested/c.rs"]);
+        assert_eq!(glob("src/?.txt"), ["src/b.txt"]);
+        assert_eq!(glob("src/b.txt"), ["src/b.txt"]);
+        assert!(glob("src/missing.txt").is_empty());
+        assert!(glob("missing/*").is_empty());
+        assert_eq!(glob("*/*.rs"), ["src/a.rs"]);
+    }
+
+    #[test]
+    fn test_expand_glob_skips_unreadable_directories() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir_all(root.join("locked")).unwrap();
+        fs::write(root.join("a.nix"), "").unwrap();
+        fs::set_permissions(root.join("locked"), fs::Permissions::from_mode(0o000)).unwrap();
+
+        let matches = expand_glob(&format!("{}/**/*.nix", root.display()));
+        fs::set_permissions(root.join("locked"), fs::Permissions::from_mode(0o755)).unwrap();
+        assert_eq!(matches, [root.join("a.nix")]);
+    }
+}
@@ -1,21 +1,27 @@
 use console::Term;
 use miette::Diagnostic;
+use nix::sys::signal::{self, Signal};
+use nix::unistd::Pid;
 use petgraph::algo::toposort;
 use petgraph::graph::{DiGraph, NodeIndex};
 use petgraph::visit::EdgeRef;
+use regex::Regex;
 
 use std::collections::BTreeMap;
 
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
 use std::fmt::Display;
+use std::io::Write;
+use std::path::PathBuf;
 use std::process::Stdio;
 use std::sync::Arc;
+use std::time::SystemTime;
 use thiserror::Error;
 use tokio::fs::File;
 use tokio::io::AsyncReadExt;
 use tokio::process::Command;
-use tokio::sync::{Notify, RwLock};
+use tokio::sync::{watch, Notify, RwLock, Semaphore};
 use tokio::task::JoinSet;
 use tokio::time::{Duration, Instant};
 use tokio::{
@@ -24,6 +30,13 @@ use tokio::{
 };
 use tracing::{error, info, instrument};
 
+mod fingerprint;
+
+use fingerprint::Fingerprint;
+
+/// How long a task gets to exit after SIGTERM before it is killed.
+const TERMINATE_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
 #[derive(Error, Diagnostic, Debug)]
 pub enum Error {
     #[error(transparent)]
@@ -32,8 +45,9 @@ pub enum Error {
     MissingCommand(String),
     TasksNotFound(Vec<(String, String)>),
     InvalidTaskName(String),
-    // TODO: be more precies where the cycle happens
-    CycleDetected(String),
+    InvalidOutputRegex(String, regex::Error),
+    SkippedDependency(String, String),
+    CycleDetected(Vec<String>),
 }
 
 impl Display for Error {
@@ -50,7 +64,9 @@ impl Display for Error {
                     .join(", ")
             ),
             Error::TaskNotFound(task) => write!(f, "Task does not exist: {}", task),
-            Error::CycleDetected(task) => write!(f, "Cycle detected at task: {}", task),
+            Error::CycleDetected(cycle) => {
+                write!(f, "Cycle detected in tasks: {}", cycle.join(" -> "))
+            }
             Error::MissingCommand(task) => write!(
                 f,
                 "Task {} defined a status, but is missing a command",
@@ -61,6 +77,14 @@ impl Display for Error {
                 "Invalid task name: {}, expected [a-zA-Z-_]+:[a-zA-Z-_]+",
                 task
             ),
+            Error::SkippedDependency(task, dep) => {
+                write!(f, "Task {} depends on {}, which was skipped", task, dep)
+            }
+            Error::InvalidOutputRegex(task, e) => write!(
+                f,
+                "Task {} has an invalid assert_output_matches regex: {}",
+                task, e
+            ),
         }
     }
 }
@@ -78,15 +102,68 @@ pub struct TaskConfig {
     status: Option<String>,
     #[serde(default)]
     inputs: Option<serde_json::Value>,
+    #[serde(default)]
+    assert_output_matches: Option<String>,
+    /// Maximum wall time for the command, and for the status command, in seconds.
+    #[serde(default)]
+    timeout: Option<u64>,
+    /// Globs of files whose contents decide whether the task needs to run again.
+    #[serde(default)]
+    input_files: Vec<String>,
+    /// Environment variables whose values decide whether the task needs to run again.
+    #[serde(default)]
+    input_env: Vec<String>,
+    /// Files the task produces. The task runs again if any of them is missing.
+    #[serde(default)]
+    output_files: Vec<String>,
+    /// Show at most this many lines of each output stream in the terminal.
+    /// Overrides the run's `max_output_lines`.
+    #[serde(default)]
+    max_output_lines: Option<usize>,
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Default, Deserialize, Serialize)]
 pub struct Config {
     pub tasks: Vec<TaskConfig>,
     pub roots: Vec<String>,
+    /// Extra environment variables set for every task in the run.
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// Maximum wall time for the whole run, in seconds.
+    #[serde(default)]
+    pub run_timeout: Option<u64>,
+    /// Run only the dependencies of the roots, not the roots themselves.
+    #[serde(default)]
+    pub only_deps: bool,
+    /// Tasks to leave out of the run. Nothing left in the run may depend on them.
+    #[serde(default)]
+    pub skip: Vec<String>,
+    /// How many tasks may run at the same time. Defaults to the number of CPUs.
+    #[serde(default)]
+    pub max_concurrency: Option<usize>,
+    /// Where to remember the inputs of tasks declaring `input_files` or `input_env`.
+    /// Without it, such tasks always run.
+    #[serde(default)]
+    pub state_dir: Option<PathBuf>,
+    /// Run tasks even if their inputs haven't changed.
+    #[serde(default)]
+    pub force: bool,
+    /// Show at most this many of the last lines of each output stream of a task in the terminal.
+    #[serde(default)]
+    pub max_output_lines: Option<usize>,
+    /// Where to write the full output of every task, as `<task name>.log`.
+    #[serde(default)]
+    pub log_dir: Option<PathBuf>,
+    /// Print every line of the tasks' output as it is written, above the progress UI.
+    /// With `max_output_lines`, only the first lines of each task are printed.
+    #[serde(default)]
+    pub stream_output: bool,
+    /// Don't prefix streamed lines with the name of the task that wrote them.
+    #[serde(default)]
+    pub no_prefix: bool,
 }
 
-#[derive(Serialize)]
+#[derive(Debug, Clone, Serialize)]
 pub struct Outputs(BTreeMap<String, serde_json::Value>);
 #[derive(Debug, Clone)]
 pub struct Output(Option<serde_json::Value>);
@@ -108,11 +185,52 @@ impl std::ops::Deref for Outputs {
     }
 }
 
+/// Lines written by running tasks, waiting to be printed by the progress UI.
+#[derive(Debug)]
+struct LiveOutput {
+    lines: std::sync::Mutex<Vec<String>>,
+    // How many lines each task has streamed so far
+    counts: std::sync::Mutex<HashMap<String, usize>>,
+    max_lines: Option<usize>,
+    prefix: bool,
+    notify: Arc<Notify>,
+}
+
+impl LiveOutput {
+    /// Queue a line of `task`'s output. Once a task has streamed `max_lines` lines
+    /// (or the task's own limit), the rest is only written to its log file.
+    fn push(&self, task: &str, line: &str, max_lines: Option<usize>) {
+        let count = {
+            let mut counts = self.counts.lock().unwrap();
+            let count = counts.entry(task.to_string()).or_default();
+            *count += 1;
+            *count
+        };
+        let line = match max_lines.or(self.max_lines) {
+            Some(max) if count == max + 1 => "...(truncated)",
+            Some(max) if count > max => return,
+            _ => line,
+        };
+        let line = if self.prefix {
+            format!("[{task}] {line}")
+        } else {
+            line.to_string()
+        };
+        self.lines.lock().unwrap().push(line);
+        self.notify.notify_one();
+    }
+
+    fn take(&self) -> Vec<String> {
+        std::mem::take(&mut *self.lines.lock().unwrap())
+    }
+}
+
 #[derive(Debug, Clone)]
 struct TaskFailure {
     stdout: LinesOutput,
     stderr: LinesOutput,
     error: String,
+    exit_code: Option<i32>,
 }
 
 #[derive(Debug, Clone)]
@@ -127,13 +245,21 @@ enum TaskCompleted {
     Skipped(Skipped),
     Failed(Duration, TaskFailure),
     DependencyFailed,
+    /// Cut off because the run exceeded its `run_timeout`.
+    /// Carries the duration if the task had already started.
+    Aborted(Option<Duration>),
+    /// Terminated because the command ran longer than the task's `timeout`.
+    TimedOut(Duration, TaskFailure),
 }
 
 impl TaskCompleted {
     fn has_failed(&self) -> bool {
         matches!(
             self,
-            TaskCompleted::Failed(_, _) | TaskCompleted::DependencyFailed
+            TaskCompleted::Failed(_, _)
+                | TaskCompleted::DependencyFailed
+                | TaskCompleted::Aborted(_)
+                | TaskCompleted::TimedOut(_, _)
         )
     }
 }
@@ -148,25 +274,73 @@ enum TaskStatus {
 #[derive(Debug)]
 struct TaskState {
     task: TaskConfig,
+    output_regex: Option<Regex>,
     status: TaskStatus,
+    // Wall clock times for reporting
+    started_at: Option<SystemTime>,
+    finished_at: Option<SystemTime>,
+    // Where the inputs of the last successful run are remembered, if the task declares any
+    fingerprint_file: Option<PathBuf>,
+    // Where the full output of the command is written
+    log_file: Option<PathBuf>,
+    // Where lines of output are sent as they are read, if streaming
+    live_output: Option<Arc<LiveOutput>>,
+    force: bool,
 }
 
 impl TaskState {
-    fn new(task: TaskConfig) -> Self {
-        Self {
+    fn new(task: TaskConfig, config: &Config) -> Result<Self, Error> {
+        let output_regex = match &task.assert_output_matches {
+            Some(pattern) => Some(
+                Regex::new(pattern).map_err(|e| Error::InvalidOutputRegex(task.name.clone(), e))?,
+            ),
+            None => None,
+        };
+        let fingerprint_file = config
+            .state_dir
+            .as_ref()
+            .filter(|_| !task.input_files.is_empty() || !task.input_env.is_empty())
+            .map(|dir| dir.join(format!("{}.json", task.name)));
+        let log_file = config
+            .log_dir
+            .as_ref()
+            .map(|dir| dir.join(format!("{}.log", task.name)));
+        Ok(Self {
             task,
+            output_regex,
             status: TaskStatus::Pending,
+            started_at: None,
+            finished_at: None,
+            fingerprint_file,
+            log_file,
+            live_output: None,
+            force: config.force,
+        })
+    }
+
+    fn stream(&self, line: &str) {
+        if let Some(live_output) = &self.live_output {
+            live_output.push(&self.task.name, line, self.task.max_output_lines);
         }
     }
 
     fn prepare_command(
         &self,
         cmd: &str,
+        env: &BTreeMap<String, String>,
         outputs: &BTreeMap<String, serde_json::Value>,
     ) -> (Command, tempfile::NamedTempFile) {
         let mut command = Command::new(cmd);
         command.stdout(Stdio::piped()).stderr(Stdio::piped());
 
+        // Give every task its own process group,
+        // so that terminating it also reaches the processes it spawned.
+        std::os::unix::process::CommandExt::process_group(command.as_std_mut(), 0);
+
+        // Set environment variables passed to the run.
+        // Exports from task outputs below take precedence.
+        command.envs(env);
+
         // Set DEVENV_TASK_INPUTS
         if let Some(inputs) = &self.task.inputs {
             command.env("DEVENV_TASK_INPUT", serde_json::to_string(inputs).unwrap());
@@ -217,39 +391,229 @@ impl TaskState {
         Output(output)
     }
 
+    /// Check the combined output of a task against `assert_output_matches`.
+    ///
+    /// On a match, the capture groups are added to the task outputs under `captures`.
+    fn assert_output(
+        &self,
+        output: Output,
+        stdout: &LinesOutput,
+        stderr: &LinesOutput,
+    ) -> Result<Output, String> {
+        let Some(regex) = &self.output_regex else {
+            return Ok(output);
+        };
+
+        let combined = stdout
+            .iter()
+            .chain(stderr.iter())
+            .map(|(_, line)| line.as_str())
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        let Some(captures) = regex.captures(&combined) else {
+            let lines = combined.lines().collect::<Vec<_>>();
+            let snippet = lines[lines.len().saturating_sub(10)..].join("\n");
+            return Err(format!(
+                "Output did not match `{}`:\n{}",
+                regex.as_str(),
+                snippet
+            ));
+        };
+
+        let mut groups = serde_json::Map::new();
+        for (i, name) in regex.capture_names().enumerate().skip(1) {
+            if let Some(m) = captures.get(i) {
+                let key = name.map(String::from).unwrap_or_else(|| i.to_string());
+                groups.insert(key, serde_json::Value::String(m.as_str().to_string()));
+            }
+        }
+        if groups.is_empty() {
+            return Ok(output);
+        }
+
+        let mut value = match output.0 {
+            Some(serde_json::Value::Object(obj)) => obj,
+            _ => serde_json::Map::new(),
+        };
+        value.insert("captures".to_string(), serde_json::Value::Object(groups));
+        Ok(Output(Some(serde_json::Value::Object(value))))
+    }
+
+    /// Ask the task's process group to stop with SIGTERM,
+    /// and SIGKILL it if the task is still around after the grace period.
+    ///
+    /// The group is only signalled while its leader hasn't been reaped,
+    /// as its ID may be reused afterwards.
+    async fn terminate(child: &mut tokio::process::Child) {
+        let Some(pid) = child.id().map(|pid| Pid::from_raw(pid as i32)) else {
+            return;
+        };
+        let _ = signal::killpg(pid, Signal::SIGTERM);
+        if tokio::time::timeout(TERMINATE_GRACE_PERIOD, child.wait())
+            .await
+            .is_err()
+        {
+            let _ = signal::killpg(pid, Signal::SIGKILL);
+            let _ = child.wait().await;
+        }
+    }
+
+    /// Run the `status` command, returning whether it succeeded, i.e. the task can be skipped.
+    ///
+    /// Like the task's command, it is terminated when it exceeds the task's `timeout`
+    /// or the run is cancelled, and the task completes with that outcome.
+    async fn run_status(
+        &self,
+        mut command: Command,
+        now: Instant,
+        cancel: &mut watch::Receiver<bool>,
+    ) -> Result<bool, TaskCompleted> {
+        command.stdout(Stdio::null()).stderr(Stdio::null());
+        let failed = |error: String| TaskFailure {
+            exit_code: None,
+            stdout: Vec::new(),
+            stderr: Vec::new(),
+            error,
+        };
+        let mut child = command
+            .spawn()
+            .map_err(|e| TaskCompleted::Failed(now.elapsed(), failed(e.to_string())))?;
+
+        let timeout = self.task.timeout.map(Duration::from_secs);
+        let timed_out = async {
+            match timeout {
+                Some(timeout) => tokio::time::sleep(timeout).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            result = child.wait() => match result {
+                Ok(status) => Ok(status.success()),
+                Err(e) => Err(TaskCompleted::Failed(now.elapsed(), failed(e.to_string()))),
+            },
+            true = async { cancel.wait_for(|cancelled| *cancelled).await.is_ok() } => {
+                Self::terminate(&mut child).await;
+                Err(TaskCompleted::Aborted(Some(now.elapsed())))
+            }
+            _ = timed_out => {
+                Self::terminate(&mut child).await;
+                Err(TaskCompleted::TimedOut(
+                    now.elapsed(),
+                    failed(format!(
+                        "Status command timed out after {}s",
+                        timeout.unwrap_or_default().as_secs()
+                    )),
+                ))
+            }
+        }
+    }
+
     #[instrument(ret)]
     async fn run(
         &self,
         now: Instant,
+        env: &BTreeMap<String, String>,
         outputs: &BTreeMap<String, serde_json::Value>,
+        cancel: watch::Receiver<bool>,
     ) -> TaskCompleted {
-        if let Some(cmd) = &self.task.status {
-            let (mut command, outputs_file) = self.prepare_command(cmd, outputs);
-
-            let result = command.status().await;
-            match result {
-                Ok(status) => {
-                    if status.success() {
-                        return TaskCompleted::Skipped(Skipped::Cached(
-                            Self::get_outputs(&outputs_file).await,
-                        ));
-                    }
+        let Some(fingerprint_file) = &self.fingerprint_file else {
+            return self.run_command(now, env, outputs, cancel).await;
+        };
+
+        let hash = match self.input_hash(env).await {
+            Ok(hash) => hash,
+            Err(e) => {
+                error!("Failed to hash the inputs of {}: {}", self.task.name, e);
+                return self.run_command(now, env, outputs, cancel).await;
+            }
+        };
+
+        if !self.force {
+            if let Some(fingerprint) = Fingerprint::load(fingerprint_file) {
+                if fingerprint.hash == hash && self.outputs_exist().await {
+                    return TaskCompleted::Skipped(Skipped::Cached(Output(fingerprint.output)));
                 }
-                Err(e) => {
-                    // TODO: stdout, stderr
-                    return TaskCompleted::Failed(
-                        now.elapsed(),
-                        TaskFailure {
-                            stdout: Vec::new(),
-                            stderr: Vec::new(),
-                            error: e.to_string(),
-                        },
-                    );
+            }
+        }
+
+        let completed = self.run_command(now, env, outputs, cancel).await;
+        if let TaskCompleted::Success(_, Output(output)) = &completed {
+            let fingerprint = Fingerprint {
+                hash,
+                output: output.clone(),
+            };
+            if let Err(e) = fingerprint.store(fingerprint_file) {
+                error!("Failed to store the inputs of {}: {}", self.task.name, e);
+            }
+        }
+        completed
+    }
+
+    /// Hash the task's inputs, off the async runtime as it reads files.
+    async fn input_hash(&self, env: &BTreeMap<String, String>) -> std::io::Result<String> {
+        let command = self.task.command.clone().unwrap_or_default();
+        let files = self.task.input_files.clone();
+        let input_env = self.task.input_env.clone();
+        let env = env.clone();
+        tokio::task::spawn_blocking(move || fingerprint::hash(&command, &files, &input_env, &env))
+            .await
+            .map_err(std::io::Error::other)?
+    }
+
+    async fn outputs_exist(&self) -> bool {
+        let patterns = self.task.output_files.clone();
+        tokio::task::spawn_blocking(move || {
+            patterns
+                .iter()
+                .all(|pattern| !fingerprint::expand_glob(pattern).is_empty())
+        })
+        .await
+        .unwrap_or(false)
+    }
+
+    /// What running the task would do, without running any of its commands.
+    async fn planned_action(&self, env: &BTreeMap<String, String>) -> PlannedAction {
+        if let Some(fingerprint_file) = self.fingerprint_file.as_ref().filter(|_| !self.force) {
+            let unchanged = self
+                .input_hash(env)
+                .await
+                .ok()
+                .zip(Fingerprint::load(fingerprint_file))
+                .is_some_and(|(hash, fingerprint)| fingerprint.hash == hash);
+            if unchanged && self.outputs_exist().await {
+                return PlannedAction::Cached;
+            }
+        }
+        match (&self.task.command, &self.task.status) {
+            // Only running the status command could tell if the task is up to date
+            (Some(_), Some(_)) => PlannedAction::CheckStatus,
+            (Some(_), None) => PlannedAction::Run,
+            (None, _) => PlannedAction::Skip,
+        }
+    }
+
+    async fn run_command(
+        &self,
+        now: Instant,
+        env: &BTreeMap<String, String>,
+        outputs: &BTreeMap<String, serde_json::Value>,
+        mut cancel: watch::Receiver<bool>,
+    ) -> TaskCompleted {
+        if let Some(cmd) = &self.task.status {
+            let (command, outputs_file) = self.prepare_command(cmd, env, outputs);
+            match self.run_status(command, now, &mut cancel).await {
+                Ok(true) => {
+                    return TaskCompleted::Skipped(Skipped::Cached(
+                        Self::get_outputs(&outputs_file).await,
+                    ));
                 }
+                Ok(false) => {}
+                Err(completed) => return completed,
             }
         }
         if let Some(cmd) = &self.task.command {
-            let (mut command, outputs_file) = self.prepare_command(cmd, outputs);
+            let (mut command, outputs_file) = self.prepare_command(cmd, env, outputs);
 
             let result = command.spawn();
 
@@ -259,6 +623,7 @@ impl TaskState {
                     return TaskCompleted::Failed(
                         now.elapsed(),
                         TaskFailure {
+                            exit_code: None,
                             stdout: Vec::new(),
                             stderr: Vec::new(),
                             error: e.to_string(),
@@ -273,6 +638,7 @@ impl TaskState {
                     return TaskCompleted::Failed(
                         now.elapsed(),
                         TaskFailure {
+                            exit_code: None,
                             stdout: Vec::new(),
                             stderr: Vec::new(),
                             error: "Failed to capture stdout".to_string(),
@@ -286,6 +652,7 @@ impl TaskState {
                     return TaskCompleted::Failed(
                         now.elapsed(),
                         TaskFailure {
+                            exit_code: None,
                             stdout: Vec::new(),
                             stderr: Vec::new(),
                             error: "Failed to capture stderr".to_string(),
@@ -300,12 +667,42 @@ impl TaskState {
             let mut stdout_lines = Vec::new();
             let mut stderr_lines = Vec::new();
 
+            let mut log = self.log_file.as_ref().and_then(|path| {
+                let file = path
+                    .parent()
+                    .map_or(Ok(()), std::fs::create_dir_all)
+                    .and_then(|_| std::fs::File::create(path));
+                match file {
+                    Ok(file) => Some(std::io::LineWriter::new(file)),
+                    Err(e) => {
+                        error!("Failed to create the log of {}: {}", self.task.name, e);
+                        None
+                    }
+                }
+            });
+            let mut write_log = |line: &str| {
+                if let Some(log) = &mut log {
+                    let _ = writeln!(log, "{line}");
+                }
+            };
+
+            let timeout = self.task.timeout.map(Duration::from_secs);
+            let timed_out = async {
+                match timeout {
+                    Some(timeout) => tokio::time::sleep(timeout).await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::pin!(timed_out);
+
             loop {
                 tokio::select! {
                     result = stdout_reader.next_line() => {
                         match result {
                             Ok(Some(line)) => {
                                 info!(stdout = %line);
+                                write_log(&line);
+                                self.stream(&line);
                                 stdout_lines.push((std::time::Instant::now(), line));
                             },
                             Ok(None) => {},
@@ -318,6 +715,8 @@ impl TaskState {
                     result = stderr_reader.next_line() => {
                         match result {
                             Ok(Some(line)) => {
+                                write_log(&line);
+                                self.stream(&line);
                                 stderr_lines.push((std::time::Instant::now(), line));
                             },
                             Ok(None) => {},
@@ -326,15 +725,59 @@ impl TaskState {
                             },
                         }
                     }
+                    true = async { cancel.wait_for(|cancelled| *cancelled).await.is_ok() } => {
+                        Self::terminate(&mut child).await;
+                        return TaskCompleted::Aborted(Some(now.elapsed()));
+                    }
+                    _ = &mut timed_out => {
+                        Self::terminate(&mut child).await;
+                        return TaskCompleted::TimedOut(
+                            now.elapsed(),
+                            TaskFailure {
+                                exit_code: None,
+                                stdout: stdout_lines,
+                                stderr: stderr_lines,
+                                error: format!(
+                                    "Task timed out after {}s",
+                                    timeout.unwrap_or_default().as_secs()
+                                ),
+                            },
+                        );
+                    }
                     result = child.wait() => {
                         match result {
                             Ok(status) => {
+                                // Read whatever output is left after the process exited
+                                while let Ok(Some(line)) = stdout_reader.next_line().await {
+                                    write_log(&line);
+                                    self.stream(&line);
+                                    stdout_lines.push((std::time::Instant::now(), line));
+                                }
+                                while let Ok(Some(line)) = stderr_reader.next_line().await {
+                                    write_log(&line);
+                                    self.stream(&line);
+                                    stderr_lines.push((std::time::Instant::now(), line));
+                                }
+
                                 if status.success() {
-                                    return TaskCompleted::Success(now.elapsed(), Self::get_outputs(&outputs_file).await);
+                                    let output = Self::get_outputs(&outputs_file).await;
+                                    return match self.assert_output(output, &stdout_lines, &stderr_lines) {
+                                        Ok(output) => TaskCompleted::Success(now.elapsed(), output),
+                                        Err(error) => TaskCompleted::Failed(
+                                            now.elapsed(),
+                                            TaskFailure {
+                                                exit_code: Some(0),
+                                                stdout: stdout_lines,
+                                                stderr: stderr_lines,
+                                                error,
+                                            },
+                                        ),
+                                    };
                                 } else {
                                     return TaskCompleted::Failed(
                                         now.elapsed(),
                                         TaskFailure {
+                                            exit_code: status.code(),
                                             stdout: stdout_lines,
                                             stderr: stderr_lines,
                                             error: format!("Task exited with status: {}", status),
@@ -347,6 +790,7 @@ impl TaskState {
                                 return TaskCompleted::Failed(
                                     now.elapsed(),
                                     TaskFailure {
+                                        exit_code: None,
                                         stdout: stdout_lines,
                                         stderr: stderr_lines,
                                         error: format!("Error waiting for command: {}", e),
@@ -358,7 +802,7 @@ impl TaskState {
                 }
             }
         } else {
-            return TaskCompleted::Skipped(Skipped::NotImplemented);
+            TaskCompleted::Skipped(Skipped::NotImplemented)
         }
     }
 }
@@ -366,21 +810,42 @@ impl TaskState {
 #[derive(Debug)]
 struct Tasks {
     roots: Vec<NodeIndex>,
+    only_deps: bool,
+    skip: HashSet<NodeIndex>,
     // Stored for reporting
     root_names: Vec<String>,
     longest_task_name: usize,
+    max_output_lines: Option<usize>,
+    env: Arc<BTreeMap<String, String>>,
+    run_timeout: Option<Duration>,
+    // Flipped to true once the run exceeds `run_timeout`
+    cancel: Arc<watch::Sender<bool>>,
+    // Permits for running tasks, see `max_concurrency`
+    concurrency: Arc<Semaphore>,
     graph: DiGraph<Arc<RwLock<TaskState>>, ()>,
     tasks_order: Vec<NodeIndex>,
     notify_finished: Arc<Notify>,
     notify_ui: Arc<Notify>,
+    live_output: Option<Arc<LiveOutput>>,
 }
 
 impl Tasks {
-    async fn new(config: Config) -> Result<Self, Error> {
+    async fn new(mut config: Config) -> Result<Self, Error> {
         let mut graph = DiGraph::new();
         let mut task_indices = HashMap::new();
         let mut longest_task_name = 0;
-        for task in config.tasks {
+        let task_configs = std::mem::take(&mut config.tasks);
+        let notify_ui = Arc::new(Notify::new());
+        let live_output = config.stream_output.then(|| {
+            Arc::new(LiveOutput {
+                lines: Default::default(),
+                counts: Default::default(),
+                max_lines: config.max_output_lines,
+                prefix: !config.no_prefix,
+                notify: Arc::clone(&notify_ui),
+            })
+        });
+        for task in task_configs {
             let name = task.name.clone();
             longest_task_name = longest_task_name.max(name.len());
             if !task.name.contains(':')
@@ -397,7 +862,9 @@ impl Tasks {
             if task.status.is_some() && task.command.is_none() {
                 return Err(Error::MissingCommand(name));
             }
-            let index = graph.add_node(Arc::new(RwLock::new(TaskState::new(task))));
+            let mut task_state = TaskState::new(task, &config)?;
+            task_state.live_output = live_output.clone();
+            let index = graph.add_node(Arc::new(RwLock::new(task_state)));
             task_indices.insert(name, index);
         }
         let mut roots = Vec::new();
@@ -408,13 +875,34 @@ impl Tasks {
                 return Err(Error::TaskNotFound(name));
             }
         }
+        let mut skip = HashSet::new();
+        for name in config.skip {
+            if let Some(index) = task_indices.get(&name) {
+                skip.insert(*index);
+            } else {
+                return Err(Error::TaskNotFound(name));
+            }
+        }
         let mut tasks = Self {
             roots,
+            only_deps: config.only_deps,
+            skip,
             root_names: config.roots,
             longest_task_name,
+            max_output_lines: config.max_output_lines,
+            env: Arc::new(config.env),
+            run_timeout: config.run_timeout.map(Duration::from_secs),
+            cancel: Arc::new(watch::channel(false).0),
+            concurrency: Arc::new(Semaphore::new(
+                config
+                    .max_concurrency
+                    .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()))
+                    .max(1),
+            )),
             graph,
             notify_finished: Arc::new(Notify::new()),
-            notify_ui: Arc::new(Notify::new()),
+            notify_ui,
+            live_output,
             tasks_order: vec![],
         };
         tasks.resolve_dependencies(task_indices).await?;
@@ -467,13 +955,23 @@ impl Tasks {
         let mut visited = HashSet::new();
         let mut to_visit = Vec::new();
 
-        // Start with root nodes
+        // Start with root nodes, or only their dependencies
         for &root_index in &self.roots {
-            to_visit.push(root_index);
+            if self.only_deps {
+                to_visit.extend(
+                    self.graph
+                        .neighbors_directed(root_index, petgraph::Direction::Incoming),
+                );
+            } else {
+                to_visit.push(root_index);
+            }
         }
 
         // Depth-first search including dependencies
         while let Some(node) = to_visit.pop() {
+            if self.skip.contains(&node) {
+                continue;
+            }
             if visited.insert(node) {
                 let new_node = subgraph.add_node(self.graph[node].clone());
                 node_map.insert(node, new_node);
@@ -483,6 +981,12 @@ impl Tasks {
                     .graph
                     .neighbors_directed(node, petgraph::Direction::Incoming)
                 {
+                    if self.skip.contains(&neighbor) {
+                        return Err(Error::SkippedDependency(
+                            self.graph[node].read().await.task.name.clone(),
+                            self.graph[neighbor].read().await.task.name.clone(),
+                        ));
+                    }
                     to_visit.push(neighbor);
                 }
             }
@@ -503,10 +1007,38 @@ impl Tasks {
         // Run topological sort on the subgraph
         match toposort(&self.graph, None) {
             Ok(indexes) => Ok(indexes),
-            Err(cycle) => Err(Error::CycleDetected(
-                self.graph[cycle.node_id()].read().await.task.name.clone(),
-            )),
+            Err(cycle) => {
+                let mut names = Vec::new();
+                for index in cycle_path(&self.graph, cycle.node_id()) {
+                    names.push(self.graph[index].read().await.task.name.clone());
+                }
+                Err(Error::CycleDetected(names))
+            }
+        }
+    }
+
+    /// Every task in the run in scheduling order, with what running it would do.
+    async fn plan(&self) -> Vec<PlannedTask> {
+        let mut levels = HashMap::new();
+        let mut plan = Vec::new();
+        for index in &self.tasks_order {
+            // One level below the deepest dependency, which comes earlier in the order
+            let level = self
+                .graph
+                .neighbors_directed(*index, petgraph::Direction::Incoming)
+                .filter_map(|dep| levels.get(&dep).map(|level| level + 1))
+                .max()
+                .unwrap_or(0);
+            levels.insert(*index, level);
+            let task_state = self.graph[*index].read().await;
+            plan.push(PlannedTask {
+                name: task_state.task.name.clone(),
+                level,
+                action: task_state.planned_action(&self.env).await,
+                command: task_state.task.command.clone(),
+            });
         }
+        plan
     }
 
     #[instrument(skip(self))]
@@ -514,89 +1046,128 @@ impl Tasks {
         let mut running_tasks = JoinSet::new();
         let outputs = Arc::new(Mutex::new(BTreeMap::new()));
 
-        for index in &self.tasks_order {
-            let task_state = &self.graph[*index];
+        let deadline = self.run_timeout.map(|timeout| {
+            let cancel = Arc::clone(&self.cancel);
+            tokio::spawn(async move {
+                tokio::time::sleep(timeout).await;
+                cancel.send_replace(true);
+            })
+        });
 
-            let mut dependency_failed = false;
+        // Start every task as soon as its dependencies are done,
+        // so that a task waiting on a slow dependency doesn't hold up independent ones
+        let mut waiting = self.tasks_order.clone();
+        while !waiting.is_empty() {
+            let mut progressed = false;
+            let mut still_waiting = Vec::new();
+
+            for index in waiting {
+                let task_state = &self.graph[index];
 
-            'dependency_check: loop {
                 let mut dependencies_completed = true;
+                let mut dependency_failed = false;
                 for dep_index in self
                     .graph
-                    .neighbors_directed(*index, petgraph::Direction::Incoming)
+                    .neighbors_directed(index, petgraph::Direction::Incoming)
                 {
                     match &self.graph[dep_index].read().await.status {
                         TaskStatus::Completed(completed) => {
                             if completed.has_failed() {
                                 dependency_failed = true;
-                                break 'dependency_check;
+                                break;
                             }
                         }
-                        TaskStatus::Pending => {
-                            dependencies_completed = false;
-                            break;
-                        }
-                        TaskStatus::Running(_) => {
+                        TaskStatus::Pending | TaskStatus::Running(_) => {
                             dependencies_completed = false;
-                            break;
                         }
                     }
                 }
 
-                if dependencies_completed {
-                    break;
+                if !dependencies_completed && !dependency_failed {
+                    still_waiting.push(index);
+                    continue;
                 }
+                progressed = true;
 
-                self.notify_finished.notified().await;
-            }
-
-            if dependency_failed {
-                let mut task_state = task_state.write().await;
-                task_state.status = TaskStatus::Completed(TaskCompleted::DependencyFailed);
-                self.notify_finished.notify_one();
-                self.notify_ui.notify_one();
-            } else {
-                let now = Instant::now();
+                // Wait for a free slot before deciding whether to start the task
+                let permit = if dependency_failed {
+                    None
+                } else {
+                    Some(
+                        Arc::clone(&self.concurrency)
+                            .acquire_owned()
+                            .await
+                            .expect("semaphore is never closed"),
+                    )
+                };
 
-                // hold write lock only to update the status
-                {
+                if *self.cancel.borrow() {
                     let mut task_state = task_state.write().await;
-                    task_state.status = TaskStatus::Running(now);
-                }
-                self.notify_ui.notify_one();
-
-                let task_state_clone = Arc::clone(task_state);
-                let outputs_clone = Arc::clone(&outputs);
-                let notify_finished_clone = Arc::clone(&self.notify_finished);
-                let notify_ui_clone = Arc::clone(&self.notify_ui);
-                running_tasks.spawn(async move {
-                    let completed = {
-                        let outputs = outputs_clone.lock().await.clone();
-                        task_state_clone.read().await.run(now, &outputs).await
-                    };
+                    task_state.status = TaskStatus::Completed(TaskCompleted::Aborted(None));
+                    self.notify_finished.notify_one();
+                    self.notify_ui.notify_one();
+                } else if dependency_failed {
+                    let mut task_state = task_state.write().await;
+                    task_state.status = TaskStatus::Completed(TaskCompleted::DependencyFailed);
+                    self.notify_finished.notify_one();
+                    self.notify_ui.notify_one();
+                } else {
+                    let now = Instant::now();
+
+                    // hold write lock only to update the status
                     {
-                        let mut task_state = task_state_clone.write().await;
-                        match &completed {
-                            TaskCompleted::Success(_, Output(Some(output))) => {
-                                outputs_clone
-                                    .lock()
-                                    .await
-                                    .insert(task_state.task.name.clone(), output.clone());
-                            }
-                            TaskCompleted::Skipped(Skipped::Cached(Output(Some(output)))) => {
-                                outputs_clone
-                                    .lock()
-                                    .await
-                                    .insert(task_state.task.name.clone(), output.clone());
+                        let mut task_state = task_state.write().await;
+                        task_state.status = TaskStatus::Running(now);
+                        task_state.started_at = Some(SystemTime::now());
+                    }
+                    self.notify_ui.notify_one();
+
+                    let task_state_clone = Arc::clone(task_state);
+                    let env_clone = Arc::clone(&self.env);
+                    let outputs_clone = Arc::clone(&outputs);
+                    let notify_finished_clone = Arc::clone(&self.notify_finished);
+                    let notify_ui_clone = Arc::clone(&self.notify_ui);
+                    let cancel = self.cancel.subscribe();
+                    running_tasks.spawn(async move {
+                        let _permit = permit;
+                        let completed = {
+                            let outputs = outputs_clone.lock().await.clone();
+                            task_state_clone
+                                .read()
+                                .await
+                                .run(now, &env_clone, &outputs, cancel)
+                                .await
+                        };
+                        {
+                            let mut task_state = task_state_clone.write().await;
+                            match &completed {
+                                TaskCompleted::Success(_, Output(Some(output))) => {
+                                    outputs_clone
+                                        .lock()
+                                        .await
+                                        .insert(task_state.task.name.clone(), output.clone());
+                                }
+                                TaskCompleted::Skipped(Skipped::Cached(Output(Some(output)))) => {
+                                    outputs_clone
+                                        .lock()
+                                        .await
+                                        .insert(task_state.task.name.clone(), output.clone());
+                                }
+                                _ => {}
                             }
-                            _ => {}
+                            task_state.status = TaskStatus::Completed(completed);
+                            task_state.finished_at = Some(SystemTime::now());
                         }
-                        task_state.status = TaskStatus::Completed(completed);
-                    }
 
-                    notify_finished_clone.notify_one();
-                    notify_ui_clone.notify_one();
-                });
+                        notify_finished_clone.notify_one();
+                        notify_ui_clone.notify_one();
+                    });
+                }
+            }
+
+            waiting = still_waiting;
+            if !waiting.is_empty() && !progressed {
+                self.notify_finished.notified().await;
             }
         }
 
@@ -607,6 +1178,10 @@ impl Tasks {
             }
         }
 
+        if let Some(deadline) = deadline {
+            deadline.abort();
+        }
+
         self.notify_finished.notify_one();
         self.notify_ui.notify_one();
         Outputs(Arc::try_unwrap(outputs).unwrap().into_inner())
@@ -622,6 +1197,8 @@ pub struct TasksStatus {
     pub failed: usize,
     pub skipped: usize,
     pub dependency_failed: usize,
+    pub aborted: usize,
+    pub timed_out: usize,
 }
 
 impl TasksStatus {
@@ -634,8 +1211,132 @@ impl TasksStatus {
             failed: 0,
             skipped: 0,
             dependency_failed: 0,
+            aborted: 0,
+            timed_out: 0,
+        }
+    }
+}
+
+/// State of a single task, as reported by `devenv tasks run --json`.
+#[derive(Debug, Clone, Serialize)]
+pub struct TaskReport {
+    pub name: String,
+    pub status: TaskReportStatus,
+    pub exit_code: Option<i32>,
+    /// Milliseconds since the Unix epoch.
+    pub started_at: Option<u64>,
+    /// Milliseconds since the Unix epoch.
+    pub finished_at: Option<u64>,
+    pub duration_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum TaskReportStatus {
+    Pending,
+    Running,
+    Success,
+    Failed,
+    Skipped,
+    Cached,
+    DependencyFailed,
+    Aborted,
+    TimedOut,
+}
+
+/// A task in a dry run, see [`TasksUi::plan`].
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct PlannedTask {
+    pub name: String,
+    /// How many dependencies deep the task is.
+    /// Tasks on the same level don't depend on each other and may run in parallel.
+    pub level: usize,
+    pub action: PlannedAction,
+    pub command: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "kebab-case")]
+pub enum PlannedAction {
+    /// The command would run.
+    Run,
+    /// The inputs haven't changed since the last successful run.
+    Cached,
+    /// The `status` command would decide whether the command runs.
+    CheckStatus,
+    /// There is no command to run.
+    Skip,
+}
+
+/// The plan of a dry run grouped by level, one task per line.
+pub fn format_plan(plan: &[PlannedTask]) -> String {
+    let action = |task: &PlannedTask| {
+        serde_json::to_value(task.action)
+            .ok()
+            .and_then(|action| action.as_str().map(str::to_string))
+            .unwrap_or_default()
+    };
+    let action_width = plan
+        .iter()
+        .map(|task| action(task).len())
+        .max()
+        .unwrap_or(0);
+    let width = plan.iter().map(|task| task.name.len()).max().unwrap_or(0);
+    let mut tasks: Vec<&PlannedTask> = plan.iter().collect();
+    tasks.sort_by_key(|task| task.level);
+    let mut out = String::new();
+    let mut level = None;
+    for task in tasks {
+        if level != Some(task.level) {
+            level = Some(task.level);
+            out.push_str(&format!("Level {}\n", task.level));
         }
+        out.push_str(&format!(
+            "  {:action_width$}  {:width$}  {}\n",
+            action(task),
+            task.name,
+            task.command.as_deref().unwrap_or("-"),
+        ));
     }
+    out
+}
+
+/// A table of every task that ran, slowest first, one per line.
+///
+/// Tasks that didn't do any work, like skipped or cached ones, are listed with 0s.
+pub fn timings(reports: &[TaskReport]) -> String {
+    let mut reports: Vec<&TaskReport> = reports
+        .iter()
+        .filter(|report| report.status != TaskReportStatus::Pending)
+        .collect();
+    reports.sort_by(|a, b| {
+        b.duration_ms
+            .unwrap_or(0)
+            .cmp(&a.duration_ms.unwrap_or(0))
+            .then_with(|| a.name.cmp(&b.name))
+    });
+    let width = reports
+        .iter()
+        .map(|report| report.name.len())
+        .max()
+        .unwrap_or(0);
+    reports
+        .iter()
+        .map(|report| {
+            let status = serde_json::to_value(&report.status).unwrap_or_default();
+            format!(
+                "{:width$}  {:>8.2}s  {}\n",
+                report.name,
+                report.duration_ms.unwrap_or(0) as f64 / 1000.0,
+                status.as_str().unwrap_or_default(),
+            )
+        })
+        .collect()
+}
+
+fn unix_millis(time: Option<SystemTime>) -> Option<u64> {
+    time.and_then(|time| time.duration_since(SystemTime::UNIX_EPOCH).ok())
+        .map(|since| since.as_millis() as u64)
 }
 
 pub struct TasksUi {
@@ -650,6 +1351,127 @@ impl TasksUi {
         })
     }
 
+    /// Run the tasks without drawing the progress UI,
+    /// writing a [`TaskReport`] as a line of JSON to `out` whenever a task starts or completes.
+    pub async fn run_json<W: Write>(
+        &mut self,
+        mut out: W,
+    ) -> Result<(TasksStatus, Outputs), Error> {
+        self.run_with(|report| {
+            serde_json::to_writer(&mut out, &report).map_err(std::io::Error::from)?;
+            writeln!(out)?;
+            out.flush()?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Run the tasks without drawing the progress UI,
+    /// calling `on_report` whenever a task starts or completes.
+    /// Streamed output is written to stderr.
+    pub async fn run_with<F>(&mut self, on_report: F) -> Result<(TasksStatus, Outputs), Error>
+    where
+        F: FnMut(TaskReport) -> Result<(), Error>,
+    {
+        self.run_with_output(on_report, |line| eprintln!("{line}"))
+            .await
+    }
+
+    /// Like [`TasksUi::run_with`], calling `on_line` with each streamed line of output.
+    pub async fn run_with_output<F, G>(
+        &mut self,
+        mut on_report: F,
+        mut on_line: G,
+    ) -> Result<(TasksStatus, Outputs), Error>
+    where
+        F: FnMut(TaskReport) -> Result<(), Error>,
+        G: FnMut(&str),
+    {
+        let tasks_clone = Arc::clone(&self.tasks);
+        let mut handle = tokio::spawn(async move { tasks_clone.run().await });
+
+        let mut emitted = HashMap::new();
+        let mut finished = None;
+        let outputs = loop {
+            for report in self.report().await {
+                if report.status == TaskReportStatus::Pending
+                    || emitted.get(&report.name) == Some(&report.status)
+                {
+                    continue;
+                }
+                emitted.insert(report.name.clone(), report.status.clone());
+                on_report(report)?;
+            }
+            if let Some(live_output) = &self.tasks.live_output {
+                for line in live_output.take() {
+                    on_line(&line);
+                }
+            }
+            // The run has finished and its final statuses were reported above
+            if let Some(outputs) = finished.take() {
+                break outputs;
+            }
+            tokio::select! {
+                result = &mut handle => finished = Some(result.unwrap()),
+                _ = self.tasks.notify_ui.notified() => {}
+            }
+        };
+
+        let tasks_status = self.get_tasks_status().await;
+        Ok((tasks_status, outputs))
+    }
+
+    /// Resolve what a run would do without running anything.
+    pub async fn plan(&self) -> Vec<PlannedTask> {
+        self.tasks.plan().await
+    }
+
+    /// Describe the outcome of every task in the run, in scheduling order.
+    pub async fn report(&self) -> Vec<TaskReport> {
+        let mut reports = Vec::new();
+        for index in &self.tasks.tasks_order {
+            let task_state = self.tasks.graph[*index].read().await;
+            let (status, exit_code, duration) = match &task_state.status {
+                TaskStatus::Pending => (TaskReportStatus::Pending, None, None),
+                TaskStatus::Running(started) => {
+                    (TaskReportStatus::Running, None, Some(started.elapsed()))
+                }
+                TaskStatus::Completed(completed) => match completed {
+                    TaskCompleted::Success(duration, _) => {
+                        (TaskReportStatus::Success, Some(0), Some(*duration))
+                    }
+                    TaskCompleted::Skipped(Skipped::Cached(_)) => {
+                        (TaskReportStatus::Cached, None, None)
+                    }
+                    TaskCompleted::Skipped(Skipped::NotImplemented) => {
+                        (TaskReportStatus::Skipped, None, None)
+                    }
+                    TaskCompleted::Failed(duration, failure) => {
+                        (TaskReportStatus::Failed, failure.exit_code, Some(*duration))
+                    }
+                    TaskCompleted::DependencyFailed => {
+                        (TaskReportStatus::DependencyFailed, None, None)
+                    }
+                    TaskCompleted::Aborted(duration) => {
+                        (TaskReportStatus::Aborted, None, *duration)
+                    }
+                    TaskCompleted::TimedOut(duration, _) => {
+                        (TaskReportStatus::TimedOut, None, Some(*duration))
+                    }
+                },
+            };
+            reports.push(TaskReport {
+                name: task_state.task.name.clone(),
+                status,
+                exit_code,
+                started_at: unix_millis(task_state.started_at),
+                finished_at: unix_millis(task_state.finished_at),
+                duration_ms: duration.map(|d| d.as_millis() as u64),
+            });
+        }
+        reports
+    }
+
     async fn get_tasks_status(&self) -> TasksStatus {
         let mut tasks_status = TasksStatus::new();
 
@@ -701,6 +1523,20 @@ impl TasksUi {
                         None,
                     )
                 }
+                TaskStatus::Completed(TaskCompleted::Aborted(duration)) => {
+                    tasks_status.aborted += 1;
+                    (
+                        console::style(format!("{:17}", "Aborted")).yellow().bold(),
+                        duration,
+                    )
+                }
+                TaskStatus::Completed(TaskCompleted::TimedOut(duration, _)) => {
+                    tasks_status.timed_out += 1;
+                    (
+                        console::style(format!("{:17}", "Timed out")).red().bold(),
+                        Some(duration),
+                    )
+                }
             };
 
             let duration = match duration {
@@ -723,22 +1559,90 @@ impl TasksUi {
         tasks_status
     }
 
-    pub async fn run(&mut self) -> Result<(TasksStatus, Outputs), Error> {
-        let names = console::style(self.tasks.root_names.join(", ")).bold();
-        let term = Term::stderr();
-        term.write_line(&format!("{:17} {}\n", "Running tasks", names))?;
-
-        // start processing tasks
-        let started = std::time::Instant::now();
-        let tasks_clone = Arc::clone(&self.tasks);
-        let handle = tokio::spawn(async move { tasks_clone.run().await });
-
-        // start TUI
+    /// The output of every failed task, as shown in the terminal after a run.
+    ///
+    /// Each stream is cut to its last `max_output_lines` lines; the full output is in the task's log file.
+    async fn failure_output(&self) -> String {
+        let mut errors = String::new();
+        for index in &self.tasks.tasks_order {
+            let task_state = self.tasks.graph[*index].read().await;
+            let TaskStatus::Completed(
+                TaskCompleted::Failed(_, failure) | TaskCompleted::TimedOut(_, failure),
+            ) = &task_state.status
+            else {
+                continue;
+            };
+            let name = &task_state.task.name;
+            let max_lines = task_state
+                .task
+                .max_output_lines
+                .or(self.tasks.max_output_lines);
+            errors.push_str(&format!(
+                "\n--- {} failed with error: {}\n",
+                name, failure.error
+            ));
+            // The output was already printed while the task ran
+            let streams = if self.tasks.live_output.is_some() {
+                vec![]
+            } else {
+                vec![("stdout", &failure.stdout), ("stderr", &failure.stderr)]
+            };
+            for (stream, lines) in streams {
+                errors.push_str(&format!("--- {} {}:\n", name, stream));
+                let hidden = max_lines.map_or(0, |max| lines.len().saturating_sub(max));
+                if hidden > 0 {
+                    errors.push_str("...(truncated)\n");
+                }
+                for (time, line) in &lines[hidden..] {
+                    errors.push_str(&format!(
+                        "{:07.2}: {}\n",
+                        time.elapsed().as_secs_f32(),
+                        line
+                    ));
+                }
+            }
+            if let Some(log_file) = &task_state.log_file {
+                errors.push_str(&format!("--- full output: {}\n", log_file.display()));
+            }
+            errors.push_str("---\n")
+        }
+        errors
+    }
+
+    pub async fn run(&mut self) -> Result<(TasksStatus, Outputs), Error> {
+        let names = console::style(self.tasks.root_names.join(", ")).bold();
+        let term = Term::stderr();
+        term.write_line(&format!("{:17} {}\n", "Running tasks", names))?;
+
+        // start processing tasks
+        let started = std::time::Instant::now();
+        let tasks_clone = Arc::clone(&self.tasks);
+        let handle = tokio::spawn(async move { tasks_clone.run().await });
+
+        // start TUI
         let mut last_list_height: u16 = 0;
 
         loop {
             let tasks_status = self.get_tasks_status().await;
 
+            // Taken after the status, so every line of a finished task is printed before the loop ends
+            let streamed = self
+                .tasks
+                .live_output
+                .as_ref()
+                .map(|live_output| live_output.take())
+                .unwrap_or_default();
+            if !streamed.is_empty() {
+                if last_list_height > 0 {
+                    term.move_cursor_up(last_list_height as usize)?;
+                    term.clear_to_end_of_screen()?;
+                    last_list_height = 0;
+                }
+                for line in streamed {
+                    term.write_line(&line)?;
+                }
+            }
+
             let status_summary = [
                 if tasks_status.pending > 0 {
                     format!(
@@ -794,6 +1698,24 @@ impl TasksUi {
                 } else {
                     String::new()
                 },
+                if tasks_status.aborted > 0 {
+                    format!(
+                        "{} {}",
+                        tasks_status.aborted,
+                        console::style("Aborted").yellow().bold()
+                    )
+                } else {
+                    String::new()
+                },
+                if tasks_status.timed_out > 0 {
+                    format!(
+                        "{} {}",
+                        tasks_status.timed_out,
+                        console::style("Timed out").red().bold()
+                    )
+                } else {
+                    String::new()
+                },
             ]
             .into_iter()
             .filter(|s| !s.is_empty())
@@ -811,7 +1733,7 @@ impl TasksUi {
                         .max(1)
                 )
             );
-            if tasks_status.lines.len() > 0 {
+            if !tasks_status.lines.is_empty() {
                 let output = console::Style::new().apply_to(output);
                 if last_list_height > 0 {
                     term.move_cursor_up(last_list_height as usize)?;
@@ -829,37 +1751,7 @@ impl TasksUi {
             self.tasks.notify_ui.notified().await;
         }
 
-        let errors = {
-            let mut errors = String::new();
-            for index in &self.tasks.tasks_order {
-                let task_state = self.tasks.graph[*index].read().await;
-                if let TaskStatus::Completed(TaskCompleted::Failed(_, failure)) = &task_state.status
-                {
-                    errors.push_str(&format!(
-                        "\n--- {} failed with error: {}\n",
-                        task_state.task.name, failure.error
-                    ));
-                    errors.push_str(&format!("--- {} stdout:\n", task_state.task.name));
-                    for (time, line) in &failure.stdout {
-                        errors.push_str(&format!(
-                            "{:07.2}: {}\n",
-                            time.elapsed().as_secs_f32(),
-                            line
-                        ));
-                    }
-                    errors.push_str(&format!("--- {} stderr:\n", task_state.task.name));
-                    for (time, line) in &failure.stderr {
-                        errors.push_str(&format!(
-                            "{:07.2}: {}\n",
-                            time.elapsed().as_secs_f32(),
-                            line
-                        ));
-                    }
-                    errors.push_str("---\n")
-                }
-            }
-            console::Style::new().apply_to(errors)
-        };
+        let errors = console::Style::new().apply_to(self.failure_output().await);
         term.write_line(&errors.to_string())?;
 
         let tasks_status = self.get_tasks_status().await;
@@ -867,6 +1759,34 @@ impl TasksUi {
     }
 }
 
+/// Find the shortest chain of dependencies leading from `start` back to itself.
+/// Edges point from a dependency to its dependent, so the walk follows incoming edges
+/// and the result reads as "depends on" from left to right, e.g. `[a, b, a]`.
+fn cycle_path<N>(graph: &DiGraph<N, ()>, start: NodeIndex) -> Vec<NodeIndex> {
+    let mut previous = HashMap::new();
+    let mut queue = std::collections::VecDeque::from([start]);
+    while let Some(node) = queue.pop_front() {
+        for dep in graph.neighbors_directed(node, petgraph::Direction::Incoming) {
+            if dep == start {
+                let mut path = vec![node];
+                let mut current = node;
+                while let Some(&prev) = previous.get(&current) {
+                    path.push(prev);
+                    current = prev;
+                }
+                path.reverse();
+                path.push(start);
+                return path;
+            }
+            if let std::collections::hash_map::Entry::Vacant(entry) = previous.entry(dep) {
+                entry.insert(node);
+                queue.push_back(dep);
+            }
+        }
+    }
+    vec![start]
+}
+
 /// Escape a shell variable by wrapping it in single quotes.
 /// Any single quotes within the variable are escaped.
 fn shell_escape(s: &str) -> String {
@@ -1002,32 +1922,149 @@ mod test {
         Ok(())
     }
 
+    async fn cycle_of(tasks: serde_json::Value) -> Vec<String> {
+        let roots: Vec<_> = tasks
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|task| task["name"].clone())
+            .collect();
+        let result =
+            Tasks::new(Config::try_from(json!({ "roots": roots, "tasks": tasks })).unwrap()).await;
+        match result {
+            Err(Error::CycleDetected(cycle)) => cycle,
+            Err(e) => panic!("Expected Error::CycleDetected, got {:?}", e),
+            Ok(_) => panic!("Expected Error::CycleDetected, got Ok"),
+        }
+    }
+
+    /// Rotate a reported cycle so it starts at `first`, since the entry point depends on graph order.
+    fn rotate_cycle(cycle: &[String], first: &str) -> Vec<String> {
+        assert_eq!(
+            cycle.first(),
+            cycle.last(),
+            "cycle must be closed: {:?}",
+            cycle
+        );
+        let ring = &cycle[..cycle.len() - 1];
+        let offset = ring
+            .iter()
+            .position(|name| name == first)
+            .unwrap_or_else(|| panic!("{} not in cycle {:?}", first, cycle));
+        let mut rotated: Vec<_> = ring[offset..]
+            .iter()
+            .chain(&ring[..offset])
+            .cloned()
+            .collect();
+        rotated.push(first.to_string());
+        rotated
+    }
+
     #[tokio::test]
     async fn test_tasks_cycle() -> Result<(), Error> {
-        let result = Tasks::new(
+        let cycle = cycle_of(json!([
+            {
+                "name": "myapp:task_1",
+                "after": ["myapp:task_2"],
+                "command": "echo 'Task 1 is running' && echo 'Task 1 completed'"
+            },
+            {
+                "name": "myapp:task_2",
+                "after": ["myapp:task_1"],
+                "command": "echo 'Task 2 is running' && echo 'Task 2 completed'"
+            }
+        ]))
+        .await;
+        assert_eq!(
+            rotate_cycle(&cycle, "myapp:task_1"),
+            ["myapp:task_1", "myapp:task_2", "myapp:task_1"]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_tasks_self_cycle() -> Result<(), Error> {
+        let cycle = cycle_of(json!([
+            {
+                "name": "myapp:task_1",
+                "after": ["myapp:task_1"],
+                "command": "echo 'Task 1 is running'"
+            }
+        ]))
+        .await;
+        assert_eq!(cycle, ["myapp:task_1", "myapp:task_1"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_tasks_long_cycle() -> Result<(), Error> {
+        let cycle = cycle_of(json!([
+            {
+                "name": "myapp:task_1",
+                "after": ["myapp:task_2"],
+                "command": "echo 'Task 1 is running'"
+            },
+            {
+                "name": "myapp:task_2",
+                "after": ["myapp:task_3"],
+                "command": "echo 'Task 2 is running'"
+            },
+            {
+                "name": "myapp:task_3",
+                "command": "echo 'Task 3 is running'"
+            },
+            {
+                "name": "myapp:task_4",
+                "before": ["myapp:task_3"],
+                "after": ["myapp:task_1"],
+                "command": "echo 'Task 4 is running'"
+            }
+        ]))
+        .await;
+        assert_eq!(
+            rotate_cycle(&cycle, "myapp:task_1"),
+            [
+                "myapp:task_1",
+                "myapp:task_2",
+                "myapp:task_3",
+                "myapp:task_4",
+                "myapp:task_1"
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_tasks_diamond_is_not_a_cycle() -> Result<(), Error> {
+        let tasks = Tasks::new(
             Config::try_from(json!({
-                "roots": ["myapp:task_1"],
+                "roots": ["myapp:task_4"],
                 "tasks": [
                     {
                         "name": "myapp:task_1",
-                        "after": ["myapp:task_2"],
-                        "command": "echo 'Task 1 is running' && echo 'Task 1 completed'"
+                        "command": "echo 'Task 1 is running'"
                     },
                     {
                         "name": "myapp:task_2",
                         "after": ["myapp:task_1"],
-                        "command": "echo 'Task 2 is running' && echo 'Task 2 completed'"
+                        "command": "echo 'Task 2 is running'"
+                    },
+                    {
+                        "name": "myapp:task_3",
+                        "after": ["myapp:task_1"],
+                        "command": "echo 'Task 3 is running'"
+                    },
+                    {
+                        "name": "myapp:task_4",
+                        "after": ["myapp:task_2", "myapp:task_3"],
+                        "command": "echo 'Task 4 is running'"
                     }
                 ]
             }))
             .unwrap(),
         )
-        .await;
-        if let Err(Error::CycleDetected(task)) = result {
-            assert_eq!(task, "myapp:task_2".to_string());
-        } else {
-            panic!("Expected Error::CycleDetected, got {:?}", result);
-        }
+        .await?;
+        assert_eq!(tasks.tasks_order.len(), 4);
         Ok(())
     }
 
@@ -1115,7 +2152,8 @@ mod test {
                     TaskFailure {
                         stdout: _,
                         stderr: _,
-                        error
+                        error,
+                        ..
                     }
                 ))
             )] if error == "No such file or directory (os error 2)" && task_1 == "myapp:task_1"
@@ -1357,6 +2395,846 @@ fi
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_env() -> Result<(), Error> {
+        let script = create_script(
+            r#"#!/bin/sh
+if [ "$FOO" != "bar" ]; then
+    echo "Expected FOO=bar, got FOO=$FOO" >&2
+    exit 1
+fi
+"#,
+        )?;
+
+        let tasks = Tasks::new(
+            Config::try_from(json!({
+                "roots": ["myapp:task_1"],
+                "env": {"FOO": "bar"},
+                "tasks": [
+                    {
+                        "name": "myapp:task_1",
+                        "command": script.to_str().unwrap()
+                    }
+                ]
+            }))
+            .unwrap(),
+        )
+        .await?;
+
+        tasks.run().await;
+
+        let task_statuses = inspect_tasks(&tasks).await;
+        let task_statuses = task_statuses.as_slice();
+        assert_matches!(
+            task_statuses,
+            [(name1, TaskStatus::Completed(TaskCompleted::Success(_, _)))] if name1 == "myapp:task_1"
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_assert_output_matches() -> Result<(), Error> {
+        let script = create_script("#!/bin/sh\necho 'version 1.2.3' && echo 'done' >&2")?;
+
+        let create_tasks = |regex: &'static str| {
+            Config::try_from(json!({
+                "roots": ["myapp:task_1"],
+                "tasks": [
+                    {
+                        "name": "myapp:task_1",
+                        "command": script.to_str().unwrap(),
+                        "assert_output_matches": regex
+                    }
+                ]
+            }))
+            .map(Tasks::new)
+            .unwrap()
+        };
+
+        let tasks = create_tasks(r"version (?<version>\d+\.\d+\.\d+)\ndone").await?;
+        let outputs = tasks.run().await;
+        assert_matches!(
+            tasks.graph[tasks.tasks_order[0]].read().await.status,
+            TaskStatus::Completed(TaskCompleted::Success(_, _))
+        );
+        assert_eq!(
+            outputs.get("myapp:task_1").unwrap(),
+            &json!({"captures": {"version": "1.2.3"}})
+        );
+
+        let tasks = create_tasks(r"version 2\.").await?;
+        tasks.run().await;
+        let task_statuses = inspect_tasks(&tasks).await;
+        assert_matches!(
+            task_statuses.as_slice(),
+            [(_, TaskStatus::Completed(TaskCompleted::Failed(_, TaskFailure { error, .. })))]
+                if error.starts_with("Output did not match `version 2\\.`") && error.contains("version 1.2.3")
+        );
+
+        assert_matches!(
+            create_tasks("(unclosed").await,
+            Err(Error::InvalidOutputRegex(_, _))
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_run_timeout() -> Result<(), Error> {
+        let slow_script = create_script("#!/bin/sh\nexec sleep 10")?;
+        let fast_script = create_script("#!/bin/sh\necho 'Task 3 completed'")?;
+
+        let tasks = Tasks::new(
+            Config::try_from(json!({
+                "roots": ["myapp:task_2"],
+                "run_timeout": 1,
+                "tasks": [
+                    {
+                        "name": "myapp:task_1",
+                        "after": ["myapp:task_3"],
+                        "command": slow_script.to_str().unwrap()
+                    },
+                    {
+                        "name": "myapp:task_2",
+                        "after": ["myapp:task_1"],
+                        "command": fast_script.to_str().unwrap()
+                    },
+                    {
+                        "name": "myapp:task_3",
+                        "command": fast_script.to_str().unwrap()
+                    }
+                ]
+            }))
+            .unwrap(),
+        )
+        .await?;
+
+        let started = Instant::now();
+        tasks.run().await;
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        let task_statuses = inspect_tasks(&tasks).await;
+        let status = |name: &str| {
+            task_statuses
+                .iter()
+                .find(|(task, _)| task == name)
+                .map(|(_, status)| status.clone())
+                .unwrap()
+        };
+        assert_matches!(
+            status("myapp:task_1"),
+            TaskStatus::Completed(TaskCompleted::Aborted(Some(_)))
+        );
+        assert_matches!(
+            status("myapp:task_2"),
+            TaskStatus::Completed(TaskCompleted::Aborted(None))
+        );
+        assert_matches!(
+            status("myapp:task_3"),
+            TaskStatus::Completed(TaskCompleted::Success(_, _))
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_status_timeout() -> Result<(), Error> {
+        let slow_status = create_script("#!/bin/sh\nexec sleep 10")?;
+        let command = create_script("#!/bin/sh\necho done")?;
+        let config = |task: serde_json::Value, run_timeout: Option<u64>| {
+            Config::try_from(json!({
+                "roots": ["myapp:task_1"],
+                "run_timeout": run_timeout,
+                "tasks": [task]
+            }))
+            .unwrap()
+        };
+        let task = |timeout: Option<u64>| {
+            json!({
+                "name": "myapp:task_1",
+                "timeout": timeout,
+                "status": slow_status.to_str().unwrap(),
+                "command": command.to_str().unwrap()
+            })
+        };
+
+        // The task's own timeout
+        let tasks = Tasks::new(config(task(Some(1)), None)).await?;
+        let started = Instant::now();
+        tasks.run().await;
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_matches!(
+            &inspect_tasks(&tasks).await[..],
+            [(_, TaskStatus::Completed(TaskCompleted::TimedOut(_, _)))]
+        );
+
+        // The run's timeout
+        let tasks = Tasks::new(config(task(None), Some(1))).await?;
+        let started = Instant::now();
+        tasks.run().await;
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_matches!(
+            &inspect_tasks(&tasks).await[..],
+            [(_, TaskStatus::Completed(TaskCompleted::Aborted(Some(_))))]
+        );
+        Ok(())
+    }
+
+    // Reads the state of the leftover process from /proc
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_task_timeout() -> Result<(), Error> {
+        let pid_file = tempfile::NamedTempFile::new()?;
+        let slow_script = create_script(&format!(
+            "#!/bin/sh\nsleep 10 &\necho $! > {}\nwait",
+            pid_file.path().display()
+        ))?;
+        let fast_script = create_script("#!/bin/sh\necho 'Task 2 completed'")?;
+
+        let tasks = Tasks::new(
+            Config::try_from(json!({
+                "roots": ["myapp:task_2"],
+                "tasks": [
+                    {
+                        "name": "myapp:task_1",
+                        "timeout": 1,
+                        "command": slow_script.to_str().unwrap()
+                    },
+                    {
+                        "name": "myapp:task_2",
+                        "after": ["myapp:task_1"],
+                        "command": fast_script.to_str().unwrap()
+                    }
+                ]
+            }))
+            .unwrap(),
+        )
+        .await?;
+
+        let started = Instant::now();
+        tasks.run().await;
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        let task_statuses = inspect_tasks(&tasks).await;
+        assert_matches!(
+            &task_statuses[..],
+            [
+                (name1, TaskStatus::Completed(TaskCompleted::TimedOut(_, _))),
+                (name2, TaskStatus::Completed(TaskCompleted::DependencyFailed))
+            ] if name1 == "myapp:task_1" && name2 == "myapp:task_2"
+        );
+
+        // The background sleep was part of the task's process group
+        let pid: i32 = fs::read_to_string(pid_file.path())?.trim().parse().unwrap();
+        let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).unwrap_or_default();
+        assert!(
+            stat.is_empty() || stat.contains(") Z "),
+            "process {} outlived its task: {}",
+            pid,
+            stat
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_timings() -> Result<(), Error> {
+        let slow_script = create_script("#!/bin/sh\nsleep 0.2")?;
+        let failing_script = create_script("#!/bin/sh\nexit 1")?;
+
+        let mut tasks_ui = TasksUi::new(
+            Config::try_from(json!({
+                "roots": ["myapp:task_3"],
+                "tasks": [
+                    {
+                        "name": "myapp:task_1",
+                        "command": slow_script.to_str().unwrap()
+                    },
+                    {
+                        "name": "myapp:task_2",
+                        "after": ["myapp:task_1"],
+                        "command": failing_script.to_str().unwrap()
+                    },
+                    {
+                        "name": "myapp:task_3",
+                        "after": ["myapp:task_2"],
+                        "command": slow_script.to_str().unwrap()
+                    }
+                ]
+            }))
+            .unwrap(),
+        )
+        .await?;
+        tasks_ui.run_json(std::io::sink()).await?;
+
+        let timings = timings(&tasks_ui.report().await);
+        let rows: Vec<Vec<&str>> = timings
+            .lines()
+            .map(|line| line.split_whitespace().collect())
+            .collect();
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows[0][0], "myapp:task_1");
+        assert!(rows[0][1].trim_end_matches('s').parse::<f64>().unwrap() >= 0.2);
+        assert_eq!(rows[1][0], "myapp:task_2");
+        assert_eq!(rows[2], ["myapp:task_3", "0.00s", "dependency-failed"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_report() -> Result<(), Error> {
+        let ok_script = create_script("#!/bin/sh\necho 'ok'")?;
+        let failing_script = create_script("#!/bin/sh\nexit 3")?;
+
+        let mut tasks_ui = TasksUi::new(
+            Config::try_from(json!({
+                "roots": ["myapp:task_3"],
+                "tasks": [
+                    {
+                        "name": "myapp:task_1",
+                        "command": ok_script.to_str().unwrap()
+                    },
+                    {
+                        "name": "myapp:task_2",
+                        "after": ["myapp:task_1"],
+                        "command": failing_script.to_str().unwrap()
+                    },
+                    {
+                        "name": "myapp:task_3",
+                        "after": ["myapp:task_2"],
+                        "command": ok_script.to_str().unwrap()
+                    }
+                ]
+            }))
+            .unwrap(),
+        )
+        .await?;
+        let mut lines = Vec::new();
+        let (status, _) = tasks_ui.run_json(&mut lines).await?;
+        assert_eq!(status.failed, 1);
+
+        // Every line is a JSON record, and the last one of each task has its final status
+        let mut last_status = BTreeMap::new();
+        for line in String::from_utf8(lines).unwrap().lines() {
+            let record: serde_json::Value = serde_json::from_str(line).unwrap();
+            last_status.insert(
+                record["name"].as_str().unwrap().to_string(),
+                record["status"].as_str().unwrap().to_string(),
+            );
+        }
+        assert_eq!(
+            last_status,
+            BTreeMap::from([
+                ("myapp:task_1".to_string(), "success".to_string()),
+                ("myapp:task_2".to_string(), "failed".to_string()),
+                ("myapp:task_3".to_string(), "dependency-failed".to_string()),
+            ])
+        );
+
+        let report = tasks_ui.report().await;
+        let summary: Vec<_> = report
+            .iter()
+            .map(|task| (task.name.as_str(), &task.status, task.exit_code))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("myapp:task_1", &TaskReportStatus::Success, Some(0)),
+                ("myapp:task_2", &TaskReportStatus::Failed, Some(3)),
+                ("myapp:task_3", &TaskReportStatus::DependencyFailed, None),
+            ]
+        );
+        assert!(report[0].started_at <= report[0].finished_at);
+        assert!(report[1].duration_ms.is_some());
+        assert_eq!(report[2].started_at, None);
+
+        let json = serde_json::to_value(&report[2]).unwrap();
+        assert_eq!(json["status"], "dependency-failed");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_independent_task_is_not_blocked() -> Result<(), Error> {
+        let marker = tempfile::tempdir()?;
+        let marker = marker.path().join("marker");
+        // Only succeeds if task_3 gets to run while task_1 is still waiting
+        let waiting_script = create_script(&format!(
+            "#!/bin/sh\nfor i in $(seq 50); do [ -e {0} ] && exit 0; sleep 0.1; done; exit 1",
+            marker.display()
+        ))?;
+        let marker_script = create_script(&format!("#!/bin/sh\ntouch {}", marker.display()))?;
+        let script = create_script("#!/bin/sh\necho 'done'")?;
+
+        let tasks = Tasks::new(
+            Config::try_from(json!({
+                "roots": ["myapp:task_2", "myapp:task_3"],
+                "max_concurrency": 3,
+                "tasks": [
+                    {
+                        "name": "myapp:task_1",
+                        "command": waiting_script.to_str().unwrap()
+                    },
+                    {
+                        "name": "myapp:task_2",
+                        "after": ["myapp:task_1"],
+                        "command": script.to_str().unwrap()
+                    },
+                    {
+                        "name": "myapp:task_3",
+                        "command": marker_script.to_str().unwrap()
+                    }
+                ]
+            }))
+            .unwrap(),
+        )
+        .await?;
+        tasks.run().await;
+
+        let task_statuses = inspect_tasks(&tasks).await;
+        assert!(
+            task_statuses.iter().all(|(_, status)| matches!(
+                status,
+                TaskStatus::Completed(TaskCompleted::Success(_, _))
+            )),
+            "{:?}",
+            task_statuses
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_input_files() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let input = dir.path().join("input.txt");
+        let output = dir.path().join("output.out");
+        let runs = dir.path().join("runs");
+        fs::write(&input, "1")?;
+        let script = create_script(&format!(
+            "#!/bin/sh\necho run >> {}\ntouch {}",
+            runs.display(),
+            output.display()
+        ))?;
+
+        let run = |force: bool| {
+            let config = json!({
+                "roots": ["myapp:task_1"],
+                "state_dir": dir.path().join("state"),
+                "force": force,
+                "tasks": [
+                    {
+                        "name": "myapp:task_1",
+                        "command": script.to_str().unwrap(),
+                        "input_files": [format!("{}/*.txt", dir.path().display())],
+                        "output_files": [output.to_str().unwrap()]
+                    }
+                ]
+            });
+            async move {
+                let tasks = Tasks::new(Config::try_from(config).unwrap()).await?;
+                tasks.run().await;
+                let status = inspect_tasks(&tasks).await.remove(0).1;
+                Ok::<_, Error>(status)
+            }
+        };
+        let run_count = || fs::read_to_string(&runs).unwrap().lines().count();
+
+        assert_matches!(
+            run(false).await?,
+            TaskStatus::Completed(TaskCompleted::Success(_, _))
+        );
+        assert_eq!(run_count(), 1);
+
+        // Nothing changed
+        assert_matches!(
+            run(false).await?,
+            TaskStatus::Completed(TaskCompleted::Skipped(Skipped::Cached(_)))
+        );
+        assert_eq!(run_count(), 1);
+
+        // An input changed
+        fs::write(&input, "2")?;
+        assert_matches!(
+            run(false).await?,
+            TaskStatus::Completed(TaskCompleted::Success(_, _))
+        );
+        assert_eq!(run_count(), 2);
+
+        // An output is missing
+        fs::remove_file(&output)?;
+        assert_matches!(
+            run(false).await?,
+            TaskStatus::Completed(TaskCompleted::Success(_, _))
+        );
+        assert_eq!(run_count(), 3);
+
+        // An input was deleted
+        fs::remove_file(&input)?;
+        assert_matches!(
+            run(false).await?,
+            TaskStatus::Completed(TaskCompleted::Success(_, _))
+        );
+        assert_eq!(run_count(), 4);
+
+        assert_matches!(
+            run(true).await?,
+            TaskStatus::Completed(TaskCompleted::Success(_, _))
+        );
+        assert_eq!(run_count(), 5);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_only_deps() -> Result<(), Error> {
+        let script = create_script("#!/bin/sh\necho 'done'")?;
+
+        let tasks = Tasks::new(
+            Config::try_from(json!({
+                "roots": ["myapp:task_3"],
+                "only_deps": true,
+                "tasks": [
+                    {
+                        "name": "myapp:task_1",
+                        "command": script.to_str().unwrap()
+                    },
+                    {
+                        "name": "myapp:task_2",
+                        "after": ["myapp:task_1"],
+                        "command": script.to_str().unwrap()
+                    },
+                    {
+                        "name": "myapp:task_3",
+                        "after": ["myapp:task_2"],
+                        "command": script.to_str().unwrap()
+                    }
+                ]
+            }))
+            .unwrap(),
+        )
+        .await?;
+        tasks.run().await;
+
+        let task_statuses = inspect_tasks(&tasks).await;
+        let task_statuses = task_statuses.as_slice();
+        assert_matches!(
+            task_statuses,
+            [
+                (name1, TaskStatus::Completed(TaskCompleted::Success(_, _))),
+                (name2, TaskStatus::Completed(TaskCompleted::Success(_, _)))
+            ] if name1 == "myapp:task_1" && name2 == "myapp:task_2"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_skip() -> Result<(), Error> {
+        let script = create_script("#!/bin/sh\necho 'done'")?;
+
+        let create_tasks = |skip: &'static str| {
+            Config::try_from(json!({
+                "roots": ["myapp:task_2", "myapp:task_3"],
+                "skip": [skip],
+                "tasks": [
+                    {
+                        "name": "myapp:task_1",
+                        "command": script.to_str().unwrap()
+                    },
+                    {
+                        "name": "myapp:task_2",
+                        "after": ["myapp:task_1"],
+                        "command": script.to_str().unwrap()
+                    },
+                    {
+                        "name": "myapp:task_3",
+                        "after": ["myapp:task_1"],
+                        "command": script.to_str().unwrap()
+                    }
+                ]
+            }))
+            .map(Tasks::new)
+            .unwrap()
+        };
+
+        let tasks = create_tasks("myapp:task_3").await?;
+        tasks.run().await;
+        let task_statuses = inspect_tasks(&tasks).await;
+        let task_statuses = task_statuses.as_slice();
+        assert_matches!(
+            task_statuses,
+            [
+                (name1, TaskStatus::Completed(TaskCompleted::Success(_, _))),
+                (name2, TaskStatus::Completed(TaskCompleted::Success(_, _)))
+            ] if name1 == "myapp:task_1" && name2 == "myapp:task_2"
+        );
+
+        assert_matches!(
+            create_tasks("myapp:task_1").await,
+            Err(Error::SkippedDependency(_, dep)) if dep == "myapp:task_1"
+        );
+        assert_matches!(
+            create_tasks("myapp:missing").await,
+            Err(Error::TaskNotFound(_))
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_max_concurrency() -> Result<(), Error> {
+        let script = create_script("#!/bin/sh\nsleep 0.3")?;
+
+        let create_tasks = |max_concurrency: usize| {
+            Config::try_from(json!({
+                "roots": ["myapp:task_1", "myapp:task_2", "myapp:task_3"],
+                "max_concurrency": max_concurrency,
+                "tasks": [
+                    {
+                        "name": "myapp:task_1",
+                        "command": script.to_str().unwrap()
+                    },
+                    {
+                        "name": "myapp:task_2",
+                        "command": script.to_str().unwrap()
+                    },
+                    {
+                        "name": "myapp:task_3",
+                        "command": script.to_str().unwrap()
+                    }
+                ]
+            }))
+            .map(Tasks::new)
+            .unwrap()
+        };
+
+        let tasks = create_tasks(1).await?;
+        let started = Instant::now();
+        tasks.run().await;
+        assert!(started.elapsed() >= Duration::from_millis(900));
+
+        let tasks = create_tasks(3).await?;
+        let started = Instant::now();
+        tasks.run().await;
+        assert!(started.elapsed() < Duration::from_millis(900));
+
+        for (_, status) in inspect_tasks(&tasks).await {
+            assert_matches!(status, TaskStatus::Completed(TaskCompleted::Success(_, _)));
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_plan() -> Result<(), Error> {
+        let state_dir = tempfile::tempdir()?;
+        let input = state_dir.path().join("input.txt");
+        fs::write(&input, "1")?;
+        let script = create_script("#!/bin/sh\necho hello")?;
+        let command = script.to_str().unwrap();
+        let status_ran = state_dir.path().join("status-ran");
+        let status = format!("touch {}", status_ran.display());
+        let config = || {
+            Config::try_from(json!({
+                "roots": ["myapp:test"],
+                "state_dir": state_dir.path(),
+                "tasks": [
+                    {
+                        "name": "myapp:codegen",
+                        "command": command,
+                        "input_files": [input.to_str().unwrap()]
+                    },
+                    {
+                        "name": "myapp:lint"
+                    },
+                    {
+                        "name": "myapp:build",
+                        "command": command,
+                        "status": status,
+                        "after": ["myapp:codegen"]
+                    },
+                    {
+                        "name": "myapp:test",
+                        "command": command,
+                        "status": "false",
+                        "after": ["myapp:build", "myapp:lint"]
+                    }
+                ]
+            }))
+            .unwrap()
+        };
+
+        let plan = |tasks: Vec<PlannedTask>| {
+            let mut tasks: Vec<_> = tasks
+                .into_iter()
+                .map(|task| (task.level, task.name, task.action))
+                .collect();
+            tasks.sort();
+            tasks
+        };
+        let expected = |codegen| {
+            vec![
+                (0, "myapp:codegen".to_string(), codegen),
+                (0, "myapp:lint".to_string(), PlannedAction::Skip),
+                (1, "myapp:build".to_string(), PlannedAction::CheckStatus),
+                (2, "myapp:test".to_string(), PlannedAction::CheckStatus),
+            ]
+        };
+
+        let tasks_ui = TasksUi::new(config()).await?;
+        assert_eq!(plan(tasks_ui.plan().await), expected(PlannedAction::Run));
+        // Planning doesn't run anything, not even status commands
+        assert_eq!(plan(tasks_ui.plan().await), expected(PlannedAction::Run));
+        assert!(!status_ran.exists());
+
+        TasksUi::new(config())
+            .await?
+            .run_json(std::io::sink())
+            .await?;
+        let tasks_ui = TasksUi::new(config()).await?;
+        assert_eq!(plan(tasks_ui.plan().await), expected(PlannedAction::Cached));
+
+        let output = format_plan(&tasks_ui.plan().await);
+        assert!(output.starts_with("Level 0\n"));
+        assert!(output.contains(&format!("  cached        myapp:codegen  {command}\n")));
+        assert!(output.contains("  skip          myapp:lint     -\n"));
+        assert!(output.contains(&format!("  check-status  myapp:build    {command}\n")));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stream_output() -> Result<(), Error> {
+        let script = create_script("#!/bin/sh\necho one\necho two >&2\nprintf three")?;
+        let config = |no_prefix| {
+            Config::try_from(json!({
+                "roots": ["myapp:task_1", "myapp:task_2"],
+                "stream_output": true,
+                "no_prefix": no_prefix,
+                "tasks": [
+                    {
+                        "name": "myapp:task_1",
+                        "command": script.to_str().unwrap()
+                    },
+                    {
+                        "name": "myapp:task_2",
+                        "command": script.to_str().unwrap()
+                    }
+                ]
+            }))
+            .unwrap()
+        };
+
+        let tasks = Tasks::new(config(false)).await?;
+        tasks.run().await;
+        let lines = tasks.live_output.as_ref().unwrap().take();
+        for task in ["myapp:task_1", "myapp:task_2"] {
+            let prefix = format!("[{task}] ");
+            let mut of_task: Vec<_> = lines
+                .iter()
+                .filter_map(|line| line.strip_prefix(&prefix))
+                .collect();
+            // stdout and stderr are read concurrently, only the lines of each are ordered
+            of_task.sort_by_key(|line| *line == "two");
+            assert_eq!(of_task, ["one", "three", "two"]);
+        }
+
+        let tasks = Tasks::new(config(true)).await?;
+        tasks.run().await;
+        let mut lines = tasks.live_output.as_ref().unwrap().take();
+        lines.sort();
+        assert_eq!(lines, ["one", "one", "three", "three", "two", "two"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stream_max_output_lines() -> Result<(), Error> {
+        let script =
+            create_script("#!/bin/sh\nfor i in 1 2 3 4 5; do echo \"line $i\"; done\nexit 1")?;
+        let mut tasks_ui = TasksUi::new(
+            Config::try_from(json!({
+                "roots": ["myapp:task_1"],
+                "stream_output": true,
+                "max_output_lines": 2,
+                "tasks": [
+                    {
+                        "name": "myapp:task_1",
+                        "command": script.to_str().unwrap()
+                    }
+                ]
+            }))
+            .unwrap(),
+        )
+        .await?;
+        let mut lines = Vec::new();
+        tasks_ui
+            .run_with_output(|_| Ok(()), |line| lines.push(line.to_string()))
+            .await?;
+        assert_eq!(
+            lines,
+            [
+                "[myapp:task_1] line 1",
+                "[myapp:task_1] line 2",
+                "[myapp:task_1] ...(truncated)"
+            ]
+        );
+
+        // The streamed output isn't repeated
+        let output = tasks_ui.failure_output().await;
+        assert!(output.contains("--- myapp:task_1 failed with error"));
+        assert!(!output.contains("line"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_max_output_lines() -> Result<(), Error> {
+        let script =
+            create_script("#!/bin/sh\nfor i in 1 2 3 4 5; do echo \"line $i\"; done\nexit 1")?;
+        let log_dir = tempfile::tempdir()?;
+
+        let mut tasks_ui = TasksUi::new(
+            Config::try_from(json!({
+                "roots": ["myapp:task_1", "myapp:task_2"],
+                "max_output_lines": 2,
+                "log_dir": log_dir.path(),
+                "tasks": [
+                    {
+                        "name": "myapp:task_1",
+                        "command": script.to_str().unwrap()
+                    },
+                    {
+                        "name": "myapp:task_2",
+                        "max_output_lines": 4,
+                        "command": script.to_str().unwrap()
+                    }
+                ]
+            }))
+            .unwrap(),
+        )
+        .await?;
+        tasks_ui.run_json(std::io::sink()).await?;
+
+        let output = tasks_ui.failure_output().await;
+        let stdout_of = |task: &str| {
+            output
+                .split(&format!("--- {task} stdout:\n"))
+                .nth(1)
+                .and_then(|rest| rest.split("--- ").next())
+                .unwrap()
+                .lines()
+                .map(|line| line.split_once(": ").map_or(line, |(_, line)| line))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            stdout_of("myapp:task_1"),
+            ["...(truncated)", "line 4", "line 5"]
+        );
+        assert_eq!(
+            stdout_of("myapp:task_2"),
+            ["...(truncated)", "line 2", "line 3", "line 4", "line 5"]
+        );
+
+        // The log file has everything
+        assert_eq!(
+            fs::read_to_string(log_dir.path().join("myapp:task_1.log"))?,
+            "line 1\nline 2\nline 3\nline 4\nline 5\n"
+        );
+        Ok(())
+    }
+
     #[cfg(test)]
     async fn inspect_tasks(tasks: &Tasks) -> Vec<(String, TaskStatus)> {
         let mut result = Vec::new();
@@ -1,6 +1,7 @@
 use clap::{Parser, Subcommand};
 use devenv_tasks::{Config, TaskConfig, TasksUi};
 use std::env;
+use std::path::PathBuf;
 
 #[derive(Parser)]
 #[clap(author, version, about)]
@@ -14,6 +15,11 @@ enum Command {
     Run {
         #[clap()]
         roots: Vec<String>,
+
+        /// Print a line of JSON whenever a task starts or completes, instead of the progress UI.
+        /// Also enabled by setting DEVENV_TASKS_JSON.
+        #[clap(long, value_enum)]
+        output: Option<OutputFormat>,
     },
     Export {
         #[clap()]
@@ -21,21 +27,46 @@ enum Command {
     },
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum OutputFormat {
+    Human,
+    Json,
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = Args::parse();
 
     match args.command {
-        Command::Run { roots } => {
+        Command::Run { roots, output } => {
             let tasks_json = env::var("DEVENV_TASKS")?;
             let tasks: Vec<TaskConfig> = serde_json::from_str(&tasks_json)?;
 
-            let config = Config { tasks, roots };
+            // Keep task state where `devenv tasks run` does, so enterShell and enterTest can skip cached tasks
+            let state_dir = env::var_os("DEVENV_DOTFILE")
+                .filter(|dotfile| !dotfile.is_empty())
+                .map(|dotfile| PathBuf::from(dotfile).join("state").join("tasks"));
+
+            let config = Config {
+                tasks,
+                roots,
+                log_dir: state_dir.as_ref().map(|dir| dir.join("logs")),
+                state_dir,
+                ..Default::default()
+            };
+
+            let output = output.unwrap_or(match env::var_os("DEVENV_TASKS_JSON") {
+                Some(value) if !value.is_empty() => OutputFormat::Json,
+                _ => OutputFormat::Human,
+            });
 
             let mut tasks_ui = TasksUi::new(config).await?;
-            let (status, _outputs) = tasks_ui.run().await?;
+            let (status, _outputs) = match output {
+                OutputFormat::Human => tasks_ui.run().await?,
+                OutputFormat::Json => tasks_ui.run_json(std::io::stdout()).await?,
+            };
 
-            if status.failed + status.dependency_failed > 0 {
+            if status.failed + status.dependency_failed + status.aborted + status.timed_out > 0 {
                 std::process::exit(1);
             }
         }
@@ -1,5 +1,6 @@
+use crate::log::LogFormat;
 use clap::{crate_version, Parser, Subcommand};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 #[derive(Parser)]
 #[command(
@@ -41,6 +42,15 @@ pub struct GlobalOptions {
     )]
     pub quiet: bool,
 
+    #[arg(
+        long,
+        global = true,
+        value_enum,
+        default_value_t = LogFormat::Human,
+        help = "How to format logs written to stderr."
+    )]
+    pub log_format: LogFormat,
+
     #[arg(short = 'j', long,
         global = true, help = "Maximum number of Nix builds at any time.",
         default_value_t = max_jobs())]
@@ -54,8 +64,14 @@ pub struct GlobalOptions {
     )]
     pub cores: u8,
 
-    #[arg(short, long, global = true, default_value_t = default_system())]
-    pub system: String,
+    #[arg(
+        short,
+        long,
+        global = true,
+        value_parser = parse_system,
+        help = "The Nix system to build for. Defaults to $DEVENV_SYSTEM, then `system` in devenv.yaml, then the current machine."
+    )]
+    pub system: Option<String>,
 
     #[arg(
         short,
@@ -84,6 +100,16 @@ pub struct GlobalOptions {
     )]
     pub refresh_eval_cache: bool,
 
+    #[arg(
+        long,
+        global = true,
+        help = "Read the Nix evaluation cache, but don't write to it."
+    )]
+    #[arg(
+        long_help = "Read the Nix evaluation cache, but don't write to it. Enabled automatically when the .devenv directory isn't writable."
+    )]
+    pub no_eval_cache_write: bool,
+
     #[arg(
         long,
         global = true,
@@ -91,6 +117,23 @@ pub struct GlobalOptions {
     )]
     pub offline: bool,
 
+    #[arg(
+        long,
+        global = true,
+        value_name = "STORE_URL",
+        value_parser = parse_store_url,
+        help = "Evaluate using this Nix store, while building in the default one."
+    )]
+    pub eval_store: Option<String>,
+
+    #[arg(
+        long,
+        global = true,
+        value_name = "PATH",
+        help = "Run Nix commands from the installation at PATH, e.g. a lix package. Overrides $DEVENV_NIX and `nix` in devenv.yaml."
+    )]
+    pub nix: Option<PathBuf>,
+
     // TODO: --no-clean?
     #[arg(
         short,
@@ -124,6 +167,15 @@ pub struct GlobalOptions {
         help = "Override inputs in devenv.yaml."
     )]
     pub override_input: Vec<String>,
+
+    #[arg(
+        long,
+        global = true,
+        value_name = "NAME=STORE_PATH",
+        value_parser = parse_pin_package,
+        help = "Replace pkgs.NAME with an existing store path. Implies --impure."
+    )]
+    pub pin_package: Vec<(String, PathBuf)>,
 }
 
 impl Default for GlobalOptions {
@@ -131,18 +183,23 @@ impl Default for GlobalOptions {
         Self {
             verbose: false,
             quiet: false,
+            log_format: LogFormat::Human,
             max_jobs: max_jobs(),
             cores: 2,
-            system: default_system(),
+            system: None,
             impure: false,
             eval_cache: true,
             no_eval_cache: false,
             refresh_eval_cache: false,
+            no_eval_cache_write: false,
             offline: false,
+            eval_store: None,
+            nix: None,
             clean: None,
             nix_debugger: false,
             nix_option: vec![],
             override_input: vec![],
+            pin_package: vec![],
         }
     }
 }
@@ -155,6 +212,29 @@ impl GlobalOptions {
             self.eval_cache = false;
         }
     }
+
+    /// Pick the system to build for, unless `--system` already did:
+    /// `$DEVENV_SYSTEM`, then `config_system` from devenv.yaml, then the current machine.
+    pub fn resolve_system(&mut self, config_system: Option<&str>) -> Result<&str, String> {
+        let system = match &self.system {
+            Some(system) => system.clone(),
+            None => match std::env::var("DEVENV_SYSTEM")
+                .ok()
+                .filter(|system| !system.is_empty())
+                .as_deref()
+                .or(config_system)
+            {
+                Some(system) => parse_system(system)?,
+                None => native_system(),
+            },
+        };
+        Ok(self.system.insert(system).as_str())
+    }
+
+    /// The system to build for, see [`GlobalOptions::resolve_system`].
+    pub fn system(&self) -> String {
+        self.system.clone().unwrap_or_else(default_system)
+    }
 }
 
 #[derive(Subcommand, Clone)]
@@ -166,6 +246,20 @@ pub enum Commands {
 
     #[command(about = "Activate the developer environment. https://devenv.sh/basics/")]
     Shell {
+        #[arg(
+            long,
+            help = "Don't print the greeting and hints when entering the shell. Same as setting DEVENV_NO_BANNER=1."
+        )]
+        no_banner: bool,
+
+        #[arg(
+            long,
+            value_name = "VAR",
+            value_parser = parse_env_name,
+            help = "Let this variable through when --clean or clean.enabled ignores the outer environment. Can be repeated."
+        )]
+        keep: Vec<String>,
+
         cmd: Option<String>,
         args: Vec<String>,
     },
@@ -180,13 +274,25 @@ pub enum Commands {
     )]
     Search {
         name: String,
+
+        #[arg(long, help = "Print the results as JSON, grouped by namespace.")]
+        json: bool,
     },
 
     #[command(
         alias = "show",
         about = "Print information about this developer environment."
     )]
-    Info {},
+    Info {
+        #[arg(
+            long,
+            help = "Report inputs with newer revisions upstream, without updating devenv.lock."
+        )]
+        check_updates: bool,
+
+        #[arg(long, requires = "check_updates", help = "Print the updates as JSON.")]
+        json: bool,
+    },
 
     #[command(about = "Start processes in the foreground. https://devenv.sh/processes/")]
     Up {
@@ -239,6 +345,11 @@ pub enum Commands {
         command: InputsCommand,
     },
 
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+
     Repl {},
 
     #[command(
@@ -246,12 +357,47 @@ pub enum Commands {
     )]
     Gc {},
 
+    #[command(about = "Remove selected state from .devenv, such as logs or caches.")]
+    Clean {
+        #[arg(long, help = "Remove process logs and task output logs.")]
+        logs: bool,
+
+        #[arg(long, help = "Remove the cache of Nix evaluations.")]
+        eval_cache: bool,
+
+        #[arg(long, help = "Remove what cached tasks remember about their inputs.")]
+        task_state: bool,
+
+        #[arg(long, help = "Remove all of the above.")]
+        all: bool,
+    },
+
+    Cache {
+        #[command(subcommand)]
+        command: CacheCommand,
+    },
+
     #[command(about = "Build any attribute in devenv.nix.")]
     Build {
         #[arg(num_args=1..)]
         attributes: Vec<String>,
     },
 
+    #[command(about = "Show why a package is part of the developer environment.")]
+    WhyDepends {
+        #[arg(help = "Name of the package, e.g. openssl or openssl-3.0.13.")]
+        package: String,
+
+        #[arg(long, help = "Print the dependency chain as JSON.")]
+        json: bool,
+    },
+
+    #[command(about = "Print the environment variables of the developer environment.")]
+    Env {
+        #[arg(long, value_enum, default_value_t = EnvFormat::Shell)]
+        format: EnvFormat,
+    },
+
     #[command(about = "Print the version of devenv.")]
     Version {},
 
@@ -266,6 +412,48 @@ pub enum Commands {
 
     #[clap(hide = true)]
     GenerateJSONSchema,
+
+    #[command(
+        hide = true,
+        about = "Print the shell completion script, e.g. `devenv completion zsh > _devenv`."
+    )]
+    Completion {
+        #[arg(value_enum)]
+        shell: CompletionShell,
+    },
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EnvFormat {
+    /// Bash script, as consumed by `nix develop`.
+    Shell,
+    /// `KEY=value` lines for systemd's `EnvironmentFile=`.
+    Systemd,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompletionShell {
+    Bash,
+    Zsh,
+    Fish,
+    Elvish,
+    Nushell,
+}
+
+impl CompletionShell {
+    /// Write the completion script for the whole CLI, generated from its clap definition.
+    pub fn generate(self, out: &mut dyn std::io::Write) {
+        use clap::CommandFactory;
+        use clap_complete::{generate, Shell};
+        let cmd = &mut Cli::command();
+        match self {
+            Self::Bash => generate(Shell::Bash, cmd, "devenv", out),
+            Self::Zsh => generate(Shell::Zsh, cmd, "devenv", out),
+            Self::Fish => generate(Shell::Fish, cmd, "devenv", out),
+            Self::Elvish => generate(Shell::Elvish, cmd, "devenv", out),
+            Self::Nushell => generate(clap_complete_nushell::Nushell, cmd, "devenv", out),
+        }
+    }
 }
 
 #[derive(Subcommand, Clone)]
@@ -281,14 +469,99 @@ pub enum ProcessesCommand {
 
     #[command(alias = "stop", about = "Stop processes running in the background.")]
     Down {},
-    // TODO: Status/Attach
+
+    #[command(about = "Show whether processes are running in the background.")]
+    Status {
+        #[arg(long, help = "Print the status as JSON.")]
+        json: bool,
+    },
+    // TODO: Attach
 }
 
 #[derive(Subcommand, Clone)]
 #[clap(about = "Run tasks. https://devenv.sh/tasks/")]
 pub enum TasksCommand {
     #[command(about = "Run tasks.")]
-    Run { tasks: Vec<String> },
+    Run {
+        tasks: Vec<String>,
+
+        #[arg(
+            long = "env",
+            value_name = "KEY=VALUE",
+            value_parser = parse_env_var,
+            help = "Set an environment variable for all tasks in the run."
+        )]
+        env: Vec<(String, String)>,
+
+        #[arg(
+            long,
+            value_name = "SECONDS",
+            help = "Abort the whole run once it has taken longer than this many seconds."
+        )]
+        run_timeout: Option<u64>,
+
+        #[arg(long, help = "Only run the dependencies of the given tasks.")]
+        only_deps: bool,
+
+        #[arg(
+            long,
+            value_name = "TASK",
+            help = "Leave a task out of the run. Fails if a remaining task depends on it."
+        )]
+        skip: Vec<String>,
+
+        #[arg(
+            long,
+            value_name = "N",
+            help = "Run at most N tasks at the same time. Defaults to the number of CPUs."
+        )]
+        max_concurrency: Option<usize>,
+
+        #[arg(
+            long,
+            help = "Print a JSON report of every task instead of the progress UI and outputs."
+        )]
+        json: bool,
+
+        #[arg(
+            long,
+            visible_alias = "no-cache",
+            help = "Run tasks even if their inputs haven't changed."
+        )]
+        force: bool,
+
+        #[arg(
+            long,
+            value_name = "N",
+            help = "Show only the last N lines of a failed task's output. The full output is kept in .devenv/state/tasks/logs."
+        )]
+        max_log_lines: Option<usize>,
+
+        #[arg(
+            long,
+            help = "Print how long each task took once the run finishes, slowest first."
+        )]
+        timings: bool,
+
+        #[arg(
+            long,
+            help = "Print the output of tasks as it is written, each line prefixed with the task name."
+        )]
+        stream: bool,
+
+        #[arg(
+            long,
+            requires = "stream",
+            help = "Print streamed output without the task name, e.g. when running a single task."
+        )]
+        no_prefix: bool,
+
+        #[arg(
+            long,
+            help = "Print which tasks would run, level by level, without running them."
+        )]
+        dry_run: bool,
+    },
 }
 
 #[derive(Subcommand, Clone)]
@@ -308,7 +581,38 @@ pub enum ContainerCommand {
 }
 
 #[derive(Subcommand, Clone)]
-#[clap(about = "Add an input to devenv.yaml. https://devenv.sh/inputs/")]
+#[clap(about = "Share the Nix evaluation cache between machines.")]
+pub enum CacheCommand {
+    #[command(
+        about = "Export the evaluation cache to a file.",
+        long_about = "Export the evaluation cache to a file.\n\nThe export is a single, compacted SQLite database rather than an archive, as the cache already lives in one database. Compress it yourself if needed."
+    )]
+    Export { file: PathBuf },
+
+    #[command(about = "Import an evaluation cache exported by the same devenv version.")]
+    Import { file: PathBuf },
+}
+
+#[derive(Subcommand, Clone)]
+#[clap(about = "Inspect devenv.yaml and devenv.local.yaml.")]
+pub enum ConfigCommand {
+    #[command(about = "Report every unknown or mistyped setting, failing if there are any.")]
+    Check {
+        #[arg(long, value_enum, default_value_t = CheckFormat::Human)]
+        format: CheckFormat,
+    },
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CheckFormat {
+    /// One `file:line:column: message` line per problem.
+    Human,
+    /// GitHub Actions workflow commands, to annotate the problems on pull requests.
+    Github,
+}
+
+#[derive(Subcommand, Clone)]
+#[clap(about = "Add or list inputs of devenv.yaml. https://devenv.sh/inputs/")]
 pub enum InputsCommand {
     #[command(about = "Add an input to devenv.yaml.")]
     Add {
@@ -323,9 +627,46 @@ pub enum InputsCommand {
         #[arg(short, long, help = "What inputs should follow your inputs?")]
         follows: Vec<String>,
     },
+
+    #[command(about = "List inputs with their locked revision and whether they are fetched.")]
+    List {
+        #[arg(long, help = "Print the inputs as JSON.")]
+        json: bool,
+    },
 }
 
+/// The systems devenv can build for, as Nix names them.
+pub const SYSTEMS: [&str; 4] = [
+    "x86_64-linux",
+    "aarch64-linux",
+    "x86_64-darwin",
+    "aarch64-darwin",
+];
+
+/// The system to build for when none is passed or configured:
+/// `$DEVENV_SYSTEM` if set to a supported system, otherwise the system devenv was compiled for.
+///
+/// Use [`GlobalOptions::resolve_system`] to report an unsupported `$DEVENV_SYSTEM` instead.
 pub fn default_system() -> String {
+    std::env::var("DEVENV_SYSTEM")
+        .ok()
+        .and_then(|system| parse_system(&system).ok())
+        .unwrap_or_else(native_system)
+}
+
+/// Check that `system` is one of [`SYSTEMS`].
+pub fn parse_system(system: &str) -> Result<String, String> {
+    if SYSTEMS.contains(&system) {
+        Ok(system.to_string())
+    } else {
+        Err(format!(
+            "`{system}` is not a supported system, expected one of {}",
+            SYSTEMS.join(", ")
+        ))
+    }
+}
+
+fn native_system() -> String {
     let arch = if cfg!(target_arch = "aarch64") {
         "aarch64"
     } else if cfg!(target_arch = "x86_64") {
@@ -346,6 +687,99 @@ pub fn default_system() -> String {
     format!("{arch}-{os}")
 }
 
+/// Parse a `KEY=VALUE` pair into an environment variable.
+fn parse_env_var(s: &str) -> Result<(String, String), String> {
+    let (key, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("invalid KEY=VALUE: no `=` found in `{s}`"))?;
+    if key.is_empty() {
+        return Err(format!("invalid KEY=VALUE: empty key in `{s}`"));
+    }
+    if !is_env_name(key) {
+        return Err(format!(
+            "invalid KEY=VALUE: `{key}` is not a valid variable name"
+        ));
+    }
+    Ok((key.to_string(), value.to_string()))
+}
+
+/// Parse the name of an environment variable.
+fn parse_env_name(s: &str) -> Result<String, String> {
+    if is_env_name(s) {
+        Ok(s.to_string())
+    } else {
+        Err(format!("`{s}` is not a valid variable name"))
+    }
+}
+
+fn is_env_name(s: &str) -> bool {
+    !s.is_empty()
+        && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && !s.starts_with(|c: char| c.is_ascii_digit())
+}
+
+fn parse_store_url(s: &str) -> Result<String, String> {
+    const SCHEMES: [&str; 8] = [
+        "file", "local", "daemon", "unix", "ssh", "ssh-ng", "http", "https",
+    ];
+    let valid = match s.split_once("://") {
+        Some((scheme, rest)) => SCHEMES.contains(&scheme) || (scheme == "s3" && !rest.is_empty()),
+        None => ["auto", "daemon", "local"].contains(&s) || s.starts_with('/'),
+    };
+    if valid {
+        Ok(s.to_string())
+    } else {
+        Err(format!(
+            "`{s}` is not a Nix store URL, expected e.g. `auto`, `daemon`, an absolute path or `ssh-ng://host`"
+        ))
+    }
+}
+
+fn parse_pin_package(s: &str) -> Result<(String, PathBuf), String> {
+    let (name, path) = s
+        .split_once('=')
+        .ok_or_else(|| format!("invalid NAME=STORE_PATH: no `=` found in `{s}`"))?;
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        return Err(format!(
+            "invalid NAME=STORE_PATH: `{name}` is not a top-level package name"
+        ));
+    }
+    let path = PathBuf::from(path);
+    let is_store_path = path.parent() == Some(Path::new("/nix/store"))
+        && path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(is_store_path_name);
+    if !is_store_path {
+        return Err(format!(
+            "invalid NAME=STORE_PATH: `{}` is not a store path",
+            path.display()
+        ));
+    }
+    Ok((name.to_string(), path))
+}
+
+/// Whether `name` is the last component of a store path: a 32 character hash and a name,
+/// e.g. `r2p2vcdkwlff8bm8bfd6q6g0nva7qv8k-hello-2.12.1`.
+fn is_store_path_name(name: &str) -> bool {
+    // The base-32 alphabet used by Nix leaves out e, o, t and u
+    const HASH_CHARS: &str = "0123456789abcdfghijklmnpqrsvwxyz";
+    let Some((hash, name)) = name.split_once('-') else {
+        return false;
+    };
+    hash.len() == 32
+        && hash.chars().all(|c| HASH_CHARS.contains(c))
+        && !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "+-._?=".contains(c))
+}
+
 fn max_jobs() -> u8 {
     let num_cpus = std::thread::available_parallelism().unwrap_or_else(|e| {
         eprintln!("Failed to get number of logical CPUs: {}", e);
@@ -356,11 +790,141 @@ fn max_jobs() -> u8 {
 
 #[cfg(test)]
 mod tests {
-    use super::Cli;
+    use super::{
+        parse_env_name, parse_env_var, parse_pin_package, parse_store_url, parse_system, Cli,
+        CompletionShell, GlobalOptions,
+    };
 
     #[test]
     fn verify_cli() {
         use clap::CommandFactory;
         Cli::command().debug_assert()
     }
+
+    #[test]
+    fn completion_follows_the_subcommands() {
+        use clap::{CommandFactory, ValueEnum};
+        for shell in CompletionShell::value_variants() {
+            let mut script = Vec::new();
+            shell.generate(&mut script);
+            let script = String::from_utf8(script).unwrap();
+            for subcommand in Cli::command()
+                .get_subcommands()
+                .filter(|subcommand| !subcommand.is_hide_set())
+            {
+                assert!(
+                    script.contains(subcommand.get_name()),
+                    "{shell:?} completion is missing {}",
+                    subcommand.get_name()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_env_var() {
+        assert_eq!(
+            parse_env_var("FOO=bar=baz"),
+            Ok(("FOO".to_string(), "bar=baz".to_string()))
+        );
+        assert_eq!(
+            parse_env_var("FOO="),
+            Ok(("FOO".to_string(), "".to_string()))
+        );
+        assert!(parse_env_var("FOO").is_err());
+        assert!(parse_env_var("=bar").is_err());
+        assert!(parse_env_var("1FOO=bar").is_err());
+        assert!(parse_env_var("FOO-BAR=baz").is_err());
+    }
+
+    #[test]
+    fn test_parse_env_name() {
+        assert_eq!(
+            parse_env_name("SSH_AUTH_SOCK"),
+            Ok("SSH_AUTH_SOCK".to_string())
+        );
+        assert_eq!(parse_env_name("_x1"), Ok("_x1".to_string()));
+        assert!(parse_env_name("").is_err());
+        assert!(parse_env_name("1PASSWORD").is_err());
+        assert!(parse_env_name("MY-VAR").is_err());
+        assert!(parse_env_name("A=B").is_err());
+    }
+
+    #[test]
+    fn test_parse_system() {
+        for system in [
+            "x86_64-linux",
+            "aarch64-linux",
+            "x86_64-darwin",
+            "aarch64-darwin",
+        ] {
+            assert_eq!(parse_system(system), Ok(system.to_string()));
+        }
+        assert!(parse_system("x86_64-windows").is_err());
+        assert!(parse_system("").is_err());
+    }
+
+    #[test]
+    fn test_resolve_system() {
+        let mut options = GlobalOptions {
+            system: Some("aarch64-linux".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            options.resolve_system(Some("x86_64-darwin")),
+            Ok("aarch64-linux")
+        );
+
+        // Only meaningful when the environment doesn't pick a system itself
+        if std::env::var_os("DEVENV_SYSTEM").is_none() {
+            let mut options = GlobalOptions::default();
+            assert_eq!(
+                options.resolve_system(Some("x86_64-darwin")),
+                Ok("x86_64-darwin")
+            );
+            assert_eq!(options.system(), "x86_64-darwin");
+            assert!(GlobalOptions::default()
+                .resolve_system(Some("sparc-solaris"))
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn test_parse_store_url() {
+        for url in [
+            "auto",
+            "daemon",
+            "/tmp/eval-store",
+            "local:///tmp/eval-store",
+            "ssh-ng://builder",
+            "s3://bucket",
+        ] {
+            assert_eq!(parse_store_url(url), Ok(url.to_string()));
+        }
+        for url in ["", "relative/store", "ftp://host", "s3://"] {
+            assert!(parse_store_url(url).is_err(), "{url} should be rejected");
+        }
+    }
+
+    #[test]
+    fn test_parse_pin_package() {
+        let hello = "/nix/store/r2p2vcdkwlff8bm8bfd6q6g0nva7qv8k-hello-2.12.1";
+        assert_eq!(
+            parse_pin_package(&format!("hello={hello}")),
+            Ok(("hello".to_string(), hello.into()))
+        );
+        assert!(parse_pin_package("hello").is_err());
+        assert!(parse_pin_package(&format!("python3Packages.requests={hello}")).is_err());
+        assert!(parse_pin_package("hello=/tmp/hello").is_err());
+        assert!(parse_pin_package(&format!("hello={hello}/bin/hello")).is_err());
+        assert!(parse_pin_package("hello=/nix/store/abc-hello-2.12").is_err());
+        // `e` isn't a base-32 digit
+        assert!(
+            parse_pin_package("hello=/nix/store/e2p2vcdkwlff8bm8bfd6q6g0nva7qv8k-hello").is_err()
+        );
+        assert!(parse_pin_package(
+            "hello=/nix/store/r2p2vcdkwlff8bm8bfd6q6g0nva7qv8k-hello\"${builtins.abort 1}\""
+        )
+        .is_err());
+    }
 }
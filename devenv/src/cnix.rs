@@ -1,4 +1,5 @@
 use crate::{cli, config, log};
+use clap::crate_version;
 use miette::{bail, IntoDiagnostic, Result, WrapErr};
 use serde::Deserialize;
 use sqlx::SqlitePool;
@@ -15,7 +16,7 @@ use std::time::{SystemTime, UNIX_EPOCH};
 pub struct Nix<'a> {
     logger: log::Logger,
     pub options: Options<'a>,
-    pool: SqlitePool,
+    pool: Option<SqlitePool>,
     // TODO: all these shouldn't be here
     config: config::Config,
     global_options: cli::GlobalOptions,
@@ -25,6 +26,8 @@ pub struct Nix<'a> {
     devenv_dot_gc: PathBuf,
     devenv_dotfile: PathBuf,
     devenv_root: PathBuf,
+    /// `None` until a command needs Nix, see [`resolve_nix`].
+    nix_root: Option<PathBuf>,
 }
 
 #[derive(Clone)]
@@ -68,10 +71,11 @@ impl Default for Options<'_> {
 }
 
 impl<'a> Nix<'a> {
+    #[allow(clippy::too_many_arguments)]
     pub async fn new<P: AsRef<Path>>(
         logger: log::Logger,
         config: config::Config,
-        global_options: cli::GlobalOptions,
+        mut global_options: cli::GlobalOptions,
         cachix_trusted_keys: P,
         devenv_home_gc: P,
         devenv_dotfile: P,
@@ -83,17 +87,48 @@ impl<'a> Nix<'a> {
         let devenv_dotfile = devenv_dotfile.as_ref().to_path_buf();
         let devenv_dot_gc = devenv_dot_gc.as_ref().to_path_buf();
         let devenv_root = devenv_root.as_ref().to_path_buf();
+        // Some commands don't run Nix, so only fail once one does
+        let nix_root = resolve_nix(&global_options, &config).ok();
 
         let cachix_caches = RefCell::new(None);
         let options = Options::default();
 
-        let database_url = format!(
-            "sqlite:{}/nix-eval-cache.db",
-            devenv_dotfile.to_string_lossy()
-        );
-        let pool = devenv_eval_cache::db::setup_db(database_url)
-            .await
-            .into_diagnostic()?;
+        if global_options.eval_cache
+            && env::var_os("DEVENV_NO_EVAL_CACHE").is_some_and(|v| v == "1")
+        {
+            logger.debug("DEVENV_NO_EVAL_CACHE is set, not using the evaluation cache");
+            global_options.eval_cache = false;
+        }
+
+        if !global_options.no_eval_cache_write && !is_writable(&devenv_dotfile) {
+            logger.debug(&format!(
+                "{} is not writable, not writing to the evaluation cache",
+                devenv_dotfile.display()
+            ));
+            global_options.no_eval_cache_write = true;
+        }
+
+        let database_path = devenv_dotfile.join("nix-eval-cache.db");
+        let database_url = format!("sqlite:{}", database_path.to_string_lossy());
+        let pool = if !global_options.no_eval_cache_write {
+            let pool = devenv_eval_cache::db::setup_db_or_recreate(&database_path)
+                .await
+                .into_diagnostic()?;
+            Some(pool)
+        } else if database_path.exists() {
+            match devenv_eval_cache::db::setup_db_read_only(database_url).await {
+                Ok(pool) => Some(pool),
+                Err(e) => {
+                    logger.warn(&format!(
+                        "Failed to open the evaluation cache, continuing without it: {e}"
+                    ));
+                    None
+                }
+            }
+        } else {
+            logger.debug("No evaluation cache to read from");
+            None
+        };
 
         Ok(Self {
             logger,
@@ -107,6 +142,7 @@ impl<'a> Nix<'a> {
             devenv_dot_gc,
             devenv_dotfile,
             devenv_root,
+            nix_root,
         })
     }
 
@@ -184,8 +220,8 @@ impl<'a> Nix<'a> {
 
     pub fn repl(&self) -> Result<()> {
         let mut cmd = self.prepare_command("nix", &["repl", "."], &self.options)?;
-        cmd.exec();
-        Ok(())
+        let err = cmd.exec();
+        bail!(err)
     }
 
     pub async fn build(&self, attributes: &[&str]) -> Result<Vec<PathBuf>> {
@@ -271,6 +307,116 @@ impl<'a> Nix<'a> {
         ))
     }
 
+    /// Write a copy of the evaluation cache to `path`.
+    pub async fn export_eval_cache(&self, path: &Path) -> Result<()> {
+        let Some(pool) = &self.pool else {
+            bail!("There is no evaluation cache to export");
+        };
+        devenv_eval_cache::export_db(pool, path, crate_version!())
+            .await
+            .into_diagnostic()
+            .wrap_err("Failed to export the evaluation cache")
+    }
+
+    /// Merge an evaluation cache exported with [`Nix::export_eval_cache`] into ours.
+    pub async fn import_eval_cache(&self, path: &Path) -> Result<u64> {
+        let Some(pool) = self
+            .pool
+            .as_ref()
+            .filter(|_| !self.global_options.no_eval_cache_write)
+        else {
+            bail!("The evaluation cache is read-only, can't import into it");
+        };
+        devenv_eval_cache::import_db(pool, path, crate_version!())
+            .await
+            .into_diagnostic()
+            .wrap_err("Failed to import the evaluation cache")
+    }
+
+    /// All store paths in the closure of `path`.
+    pub async fn closure(&self, path: &Path) -> Result<Vec<PathBuf>> {
+        let path = path.to_str().expect("store path should be utf-8");
+        let output = self
+            .run_nix("nix", &["path-info", "--recursive", path], &self.options)
+            .await?;
+        Ok(String::from_utf8_lossy(&output.stdout)
+            .split_whitespace()
+            .map(PathBuf::from)
+            .collect())
+    }
+
+    /// The chain of store paths through which `from` depends on `to`.
+    pub async fn why_depends(&self, from: &Path, to: &Path) -> Result<Vec<PathBuf>> {
+        let from = from.to_str().expect("store path should be utf-8");
+        let to = to.to_str().expect("store path should be utf-8");
+        let output = self
+            .run_nix("nix", &["why-depends", from, to], &self.options)
+            .await?;
+        Ok(parse_why_depends(&String::from_utf8_lossy(&output.stdout)))
+    }
+
+    /// Store paths of the locked inputs, as reported by `nix flake archive --dry-run`.
+    ///
+    /// Doesn't fetch anything, so inputs that can't be resolved offline are missing.
+    pub async fn input_paths(&self) -> Result<HashMap<String, PathBuf>> {
+        let options = Options {
+            bail_on_error: false,
+            ..self.options
+        };
+        let output = self
+            .run_nix(
+                "nix",
+                &["flake", "archive", "--json", "--dry-run"],
+                &options,
+            )
+            .await?;
+        if !output.status.success() {
+            self.logger
+                .debug("Failed to resolve input store paths, continuing without them.");
+            return Ok(HashMap::new());
+        }
+        let archive: serde_json::Value = serde_json::from_slice(&output.stdout)
+            .map_err(|e| miette::miette!("Failed to parse flake archive output: {}", e))?;
+        Ok(archive["inputs"]
+            .as_object()
+            .map(|inputs| {
+                inputs
+                    .iter()
+                    .filter_map(|(name, input)| {
+                        input["path"]
+                            .as_str()
+                            .map(|path| (name.clone(), PathBuf::from(path)))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    /// The newest revision of the flake reference `url`, ignoring devenv.lock.
+    ///
+    /// Returns `None` if upstream couldn't be reached or `url` doesn't point at a revision.
+    pub async fn latest_rev(&self, url: &str) -> Result<Option<String>> {
+        let options = Options {
+            bail_on_error: false,
+            ..self.options
+        };
+        let output = self
+            .run_nix(
+                "nix",
+                &["flake", "prefetch", "--json", "--refresh", url],
+                &options,
+            )
+            .await?;
+        if !output.status.success() {
+            self.logger
+                .debug(&format!("Failed to query {url} for its latest revision."));
+            return Ok(None);
+        }
+        let prefetch: serde_json::Value = serde_json::from_slice(&output.stdout)
+            .map_err(|e| miette::miette!("Failed to parse flake prefetch output: {}", e))?;
+        Ok(prefetch["locked"]["rev"].as_str().map(String::from))
+    }
+
     pub async fn search(&self, name: &str) -> Result<devenv_eval_cache::Output> {
         self.run_nix_with_substituters(
             "nix",
@@ -323,15 +469,10 @@ impl<'a> Nix<'a> {
         mut cmd: std::process::Command,
         options: &Options<'a>,
     ) -> Result<devenv_eval_cache::Output> {
+        use devenv_eval_cache::command::CommandError;
         use devenv_eval_cache::internal_log::Verbosity;
         use devenv_eval_cache::{supports_eval_caching, CachedCommand};
 
-        let mut logger = self.logger.clone();
-
-        if !options.logging {
-            logger.level = log::Level::Error;
-        }
-
         if options.replace_shell {
             if self.global_options.nix_debugger
                 && cmd.get_program().to_string_lossy().ends_with("bin/nix")
@@ -351,16 +492,27 @@ impl<'a> Nix<'a> {
                 .stderr(process::Stdio::inherit());
             if options.logging_stdout {
                 cmd.stdout(std::process::Stdio::inherit());
+            } else {
+                cmd.stdout(std::process::Stdio::piped());
             }
         }
 
-        let result = if self.global_options.eval_cache
-            && options.cache_output
-            && supports_eval_caching(&cmd)
-        {
-            let mut cached_cmd = CachedCommand::new(&self.pool);
+        let use_eval_cache =
+            self.global_options.eval_cache && options.cache_output && supports_eval_caching(&cmd);
+        let result = if let Some(pool) = self.pool.as_ref().filter(|_| use_eval_cache) {
+            let mut cached_cmd = CachedCommand::new(pool);
+
+            // The flake template and the Nix flags differ between releases.
+            // The system is only passed through .devenv.flake.nix, which isn't watched.
+            cached_cmd.salt(format!(
+                "{} {}",
+                crate_version!(),
+                self.global_options.system()
+            ));
 
             cached_cmd.watch_path(self.devenv_root.join("devenv.yaml"));
+            // Merged into .devenv/devenv.json, which isn't watched
+            cached_cmd.watch_path(self.devenv_root.join("devenv.local.yaml"));
 
             cached_cmd.unwatch_path(self.devenv_root.join(".devenv.flake.nix"));
             // Ignore anything in .devenv.
@@ -370,6 +522,10 @@ impl<'a> Nix<'a> {
                 cached_cmd.force_refresh();
             }
 
+            if self.global_options.no_eval_cache_write {
+                cached_cmd.read_only();
+            }
+
             if options.logging {
                 let target_log_level = if self.global_options.verbose {
                     Verbosity::Talkative
@@ -379,22 +535,38 @@ impl<'a> Nix<'a> {
                     Verbosity::Info
                 };
 
+                let print = self.output_printer();
                 cached_cmd.on_stderr(move |log| {
                     if let Some(msg) = log.get_log_msg_by_level(target_log_level) {
-                        eprintln!("{msg}");
+                        print(&msg);
                     }
                 });
             }
-            cached_cmd
-                .output(&mut cmd)
-                .await
-                .into_diagnostic()
-                .wrap_err_with(|| format!("Failed to run command `{}`", display_command(&cmd)))?
+            match cached_cmd.output(&mut cmd).await {
+                Ok(output) => output,
+                // Handled below like the failures of uncached commands
+                Err(CommandError::NonZeroExitStatus { status, stderr }) => {
+                    devenv_eval_cache::Output {
+                        status,
+                        stdout: vec![],
+                        stderr,
+                        paths: vec![],
+                    }
+                }
+                Err(e) => {
+                    return Err(e).into_diagnostic().wrap_err_with(|| {
+                        format!("Failed to run command `{}`", display_command(&cmd))
+                    })
+                }
+            }
         } else {
-            let output = cmd
-                .output()
-                .into_diagnostic()
-                .wrap_err_with(|| format!("Failed to run command `{}`", display_command(&cmd)))?;
+            let output = if options.logging {
+                output_teeing_stderr(&mut cmd, self.output_printer())
+            } else {
+                cmd.output()
+            }
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to run command `{}`", display_command(&cmd)))?;
             devenv_eval_cache::Output {
                 status: output.status,
                 stdout: output.stdout,
@@ -413,19 +585,26 @@ impl<'a> Nix<'a> {
                 && cmd.get_program().to_string_lossy().ends_with("bin/nix")
             {
                 self.logger.info("Starting Nix debugger ...");
-                cmd.arg("--debugger").exec();
+                let _ = cmd.arg("--debugger").exec();
             }
 
             if options.bail_on_error {
-                if options.logging {
+                let nix_error = parse_nix_error(&String::from_utf8_lossy(&result.stderr));
+                // With logging, stderr has already been printed as it was written
+                if options.logging
+                    && !result.stdout.is_empty()
+                    && (nix_error.is_none() || self.global_options.verbose)
+                {
                     eprintln!();
                     self.logger.error(&format!(
-                        "Command produced the following output:\n{}\n{}",
+                        "Command produced the following output:\n{}",
                         String::from_utf8_lossy(&result.stdout),
-                        String::from_utf8_lossy(&result.stderr),
                     ));
                 }
 
+                if let Some(nix_error) = nix_error {
+                    return Err(nix_error.into());
+                }
                 bail!(format!(
                     "Command `{}` failed with {code}",
                     display_command(&cmd)
@@ -436,6 +615,19 @@ impl<'a> Nix<'a> {
         Ok(result)
     }
 
+    /// Where Nix's own output goes while a command runs:
+    /// the event sink if the logger has one, stderr otherwise.
+    fn output_printer(&self) -> impl Fn(&str) + Send + 'static {
+        let sink = self.logger.sink().cloned();
+        move |line: &str| match &sink {
+            Some(sink) => sink(log::Event::Log {
+                level: log::Level::Info,
+                message: line.to_string(),
+            }),
+            None => eprintln!("{line}"),
+        }
+    }
+
     // We have a separate function to avoid recursion as this needs to call self.prepare_command
     pub async fn prepare_command_with_substituters(
         &self,
@@ -542,23 +734,29 @@ impl<'a> Nix<'a> {
 
         flags.extend_from_slice(args);
 
-        let mut cmd = match env::var("DEVENV_NIX") {
-            Ok(devenv_nix) => std::process::Command::new(format!("{devenv_nix}/bin/{command}")),
-            Err(_) => {
-                self.logger.error(
-            "$DEVENV_NIX is not set, but required as devenv doesn't work without a few Nix patches."
-            );
-                self.logger
-                    .error("Please follow https://devenv.sh/getting-started/ to install devenv.");
-                bail!("$DEVENV_NIX is not set")
-            }
+        let nix_root = match &self.nix_root {
+            Some(nix_root) => nix_root.clone(),
+            None => resolve_nix(&self.global_options, &self.config)?,
         };
+        let mut cmd = std::process::Command::new(nix_root.join("bin").join(command));
 
         if self.global_options.offline && command == "nix" {
             flags.push("--offline");
         }
 
-        if self.global_options.impure || self.config.impure {
+        // Only the new CLI knows about separate eval stores
+        if let Some(eval_store) = &self.global_options.eval_store {
+            if command == "nix" {
+                flags.push("--eval-store");
+                flags.push(eval_store);
+            }
+        }
+
+        // builtins.storePath, used for pinned packages, is not allowed in pure evaluation
+        let impure = self.global_options.impure
+            || self.config.impure
+            || !self.global_options.pin_package.is_empty();
+        if impure {
             // only pass the impure option to the nix command that supports it.
             // avoid passing it to the older utilities, e.g. like `nix-store` when creating GC roots.
             if command == "nix"
@@ -581,7 +779,7 @@ impl<'a> Nix<'a> {
         Ok(cmd)
     }
 
-    async fn get_cachix_caches(&self) -> Result<Ref<CachixCaches>> {
+    async fn get_cachix_caches(&self) -> Result<Ref<'_, CachixCaches>> {
         if self.cachix_caches.borrow().is_none() {
             let no_logging = Options {
                 logging: false,
@@ -607,7 +805,7 @@ impl<'a> Nix<'a> {
             for name in caches.caches.pull.iter() {
                 if !caches.known_keys.contains_key(name) {
                     let mut request =
-                        client.get(&format!("https://cachix.org/api/v1/cache/{}", name));
+                        client.get(format!("https://cachix.org/api/v1/cache/{}", name));
                     if let Ok(ret) = env::var("CACHIX_AUTH_TOKEN") {
                         request = request.bearer_auth(ret);
                     }
@@ -740,6 +938,52 @@ impl<'a> Nix<'a> {
     }
 }
 
+/// Find the Nix installation to run commands from.
+///
+/// `--nix` takes precedence over `$DEVENV_NIX`, which takes precedence over `nix` in devenv.yaml.
+/// Fails unless the installation has an executable `bin/nix`.
+pub fn resolve_nix(
+    global_options: &cli::GlobalOptions,
+    config: &config::Config,
+) -> Result<PathBuf> {
+    let env_nix = env::var_os("DEVENV_NIX").map(PathBuf::from);
+    let Some(nix_root) = nix_root(
+        global_options.nix.as_deref(),
+        env_nix.as_deref(),
+        config.nix.as_deref(),
+    ) else {
+        bail!(
+            "No Nix installation configured, but devenv doesn't work without a few Nix patches.\n\
+            Please follow https://devenv.sh/getting-started/ to install devenv, \
+            or point --nix, $DEVENV_NIX or `nix` in devenv.yaml at a Nix installation."
+        )
+    };
+    check_nix(&nix_root)?;
+    Ok(nix_root)
+}
+
+fn nix_root(flag: Option<&Path>, env: Option<&Path>, config: Option<&str>) -> Option<PathBuf> {
+    flag.or(env)
+        .or(config.map(Path::new))
+        .filter(|path| !path.as_os_str().is_empty())
+        .map(Path::to_path_buf)
+}
+
+fn check_nix(nix_root: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let nix = nix_root.join("bin").join("nix");
+    match fs::metadata(&nix) {
+        Ok(metadata) if metadata.is_file() && metadata.permissions().mode() & 0o111 != 0 => Ok(()),
+        Ok(_) => bail!("{} is not an executable file", nix.display()),
+        Err(e) => bail!(
+            "Can't use the Nix installation at {}: {} ({e})",
+            nix_root.display(),
+            nix.display()
+        ),
+    }
+}
+
 fn symlink_force(logger: &log::Logger, link_path: &Path, target: &Path) {
     let _lock = dotlock::Dotlock::create(target.with_extension("lock")).unwrap();
     logger.debug(&format!(
@@ -761,6 +1005,11 @@ fn symlink_force(logger: &log::Logger, link_path: &Path, target: &Path) {
     });
 }
 
+// Check whether we can create files in a directory.
+fn is_writable(dir: &Path) -> bool {
+    tempfile::tempfile_in(dir).is_ok()
+}
+
 fn get_now_with_nanoseconds() -> String {
     let now = SystemTime::now();
     let duration = now.duration_since(UNIX_EPOCH).expect("Time went backwards");
@@ -803,10 +1052,421 @@ struct StorePing {
     trusted: Option<u8>,
 }
 
+/// Extract the dependency chain from the tree printed by `nix why-depends`.
+///
+/// Without `--all` each line holds the next path in the chain, indented with box-drawing characters.
+fn parse_why_depends(output: &str) -> Vec<PathBuf> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let start = line.find("/nix/store/")?;
+            let path = line[start..].split_whitespace().next()?;
+            Some(PathBuf::from(path))
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NixErrorKind {
+    MissingAttribute,
+    UndefinedVariable,
+    SyntaxError,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NixErrorLocation {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl std::fmt::Display for NixErrorLocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.column)
+    }
+}
+
+/// A common Nix error, recognized in the output of a failed command.
+#[derive(Debug, thiserror::Error, miette::Diagnostic)]
+#[error("{message}{}", .location.as_ref().map(|l| format!(" at {l}")).unwrap_or_default())]
+pub struct NixError {
+    pub kind: NixErrorKind,
+    /// The error message, without the `error:` prefix.
+    pub message: String,
+    /// The missing attribute or undefined variable.
+    pub symbol: Option<String>,
+    pub location: Option<NixErrorLocation>,
+    /// Everything Nix printed, including the trace.
+    pub text: String,
+    #[help]
+    pub hint: Option<String>,
+}
+
+/// Run `cmd` to completion, passing each line of its stderr to `print` as it is written
+/// while also capturing it.
+fn output_teeing_stderr(
+    cmd: &mut process::Command,
+    print: impl Fn(&str) + Send + 'static,
+) -> std::io::Result<process::Output> {
+    use std::io::BufRead;
+
+    let mut child = cmd.stderr(process::Stdio::piped()).spawn()?;
+    let stderr = child.stderr.take().expect("stderr is piped");
+    let tee = std::thread::spawn(move || {
+        let mut captured = Vec::new();
+        for line in std::io::BufReader::new(stderr).split(b'\n') {
+            let line = line?;
+            print(&String::from_utf8_lossy(&line));
+            captured.extend_from_slice(&line);
+            captured.push(b'\n');
+        }
+        Ok::<_, std::io::Error>(captured)
+    });
+    let mut output = child.wait_with_output()?;
+    output.stderr = tee.join().expect("stderr reader panicked")?;
+    Ok(output)
+}
+
+/// Find the innermost error Nix reported in `stderr`, if it's one we know how to explain.
+fn parse_nix_error(stderr: &str) -> Option<NixError> {
+    // Messages from the internal JSON log are colored
+    let plain = console::strip_ansi_codes(stderr);
+    let lines: Vec<&str> = plain.lines().map(str::trim).collect();
+    let (index, kind, message) = lines.iter().enumerate().rev().find_map(|(index, line)| {
+        let message = line.strip_prefix("error: ")?;
+        let kind = if message.starts_with("attribute '") && message.ends_with("' missing") {
+            NixErrorKind::MissingAttribute
+        } else if message.starts_with("undefined variable '") {
+            NixErrorKind::UndefinedVariable
+        } else if message.starts_with("syntax error") {
+            NixErrorKind::SyntaxError
+        } else {
+            return None;
+        };
+        Some((index, kind, message))
+    })?;
+
+    // Older versions of Nix print the location on the same line, newer ones on the next.
+    let inline = message
+        .rsplit_once(", at ")
+        .and_then(|(message, location)| {
+            parse_nix_location(location).map(|location| (message, Some(location)))
+        });
+    let (message, location) = inline.unwrap_or_else(|| {
+        let location = lines[index + 1..]
+            .iter()
+            .find_map(|line| line.strip_prefix("at "))
+            .and_then(parse_nix_location);
+        (message, location)
+    });
+    let symbol = match kind {
+        NixErrorKind::SyntaxError => None,
+        _ => message.split('\'').nth(1).map(String::from),
+    };
+    let hint = match (kind, &symbol) {
+        (NixErrorKind::MissingAttribute, Some(symbol)) => Some(format!(
+            "Check the spelling of '{symbol}'. For packages, try `devenv search {symbol}`."
+        )),
+        (NixErrorKind::UndefinedVariable, Some(symbol)) => Some(format!(
+            "'{symbol}' isn't in scope. Add it to the arguments at the top of the file, e.g. `{{ pkgs, {symbol}, ... }}:`, or bind it with `let`."
+        )),
+        (NixErrorKind::SyntaxError, _) => Some(
+            "Look for a missing `;`, bracket or quote just before this location.".to_string(),
+        ),
+        _ => None,
+    };
+    Some(NixError {
+        kind,
+        message: message.to_string(),
+        symbol,
+        location,
+        text: stderr.to_string(),
+        hint,
+    })
+}
+
+/// Parse `file:line:column`, optionally followed by a colon.
+fn parse_nix_location(location: &str) -> Option<NixErrorLocation> {
+    let location = location.trim().trim_end_matches(':');
+    let mut parts = location.rsplitn(3, ':');
+    let column = parts.next()?.parse().ok()?;
+    let line = parts.next()?.parse().ok()?;
+    let file = parts.next()?.to_string();
+    Some(NixErrorLocation { file, line, column })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[tokio::test]
+    async fn test_eval_store_is_forwarded() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut global_options = cli::GlobalOptions::default();
+        global_options.eval_store = Some("/tmp/eval-store".to_string());
+        global_options.nix = Some(fake_nix(dir.path()));
+        let nix = Nix::new(
+            log::Logger::new(log::Level::Silent),
+            config::Config::default(),
+            global_options,
+            dir.path().join("trusted-keys.json"),
+            dir.path().join("home-gc"),
+            dir.path().to_path_buf(),
+            dir.path().join("gc"),
+            dir.path().to_path_buf(),
+        )
+        .await
+        .unwrap();
+
+        let args = |command| {
+            let cmd = nix
+                .prepare_command(command, &["eval"], &nix.options)
+                .unwrap();
+            cmd.get_args()
+                .map(|arg| arg.to_string_lossy().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        let nix_args = args("nix");
+        let position = nix_args.iter().position(|arg| arg == "--eval-store");
+        assert_eq!(
+            position.map(|i| nix_args[i + 1].as_str()),
+            Some("/tmp/eval-store")
+        );
+        assert!(!args("nix-store").contains(&"--eval-store".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_missing_nix_fails_when_running_nix() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut global_options = cli::GlobalOptions::default();
+        global_options.nix = Some(dir.path().join("missing"));
+        let nix = Nix::new(
+            log::Logger::new(log::Level::Silent),
+            config::Config::default(),
+            global_options,
+            dir.path().join("trusted-keys.json"),
+            dir.path().join("home-gc"),
+            dir.path().to_path_buf(),
+            dir.path().join("gc"),
+            dir.path().to_path_buf(),
+        )
+        .await
+        .unwrap();
+
+        let err = nix
+            .prepare_command("nix", &["eval"], &nix.options)
+            .unwrap_err();
+        assert!(err.to_string().contains("Can't use the Nix installation"));
+    }
+
+    #[tokio::test]
+    async fn test_nix_error_from_failed_command() {
+        // Plain output when run directly, the internal JSON log when run through the eval cache
+        let script = r#"#!/bin/sh
+case "$*" in
+  *internal-json*)
+    printf '%s\n' '@nix {"action":"msg","level":0,"msg":"\u001b[31;1merror:\u001b[0m undefined variable \u0027foo\u0027\n       at /project/devenv.nix:3:5:"}' >&2 ;;
+  *)
+    printf "error: undefined variable 'foo'\n       at /project/devenv.nix:3:5:\n" >&2 ;;
+esac
+exit 1
+"#;
+        for eval_cache in [false, true] {
+            let dir = tempfile::tempdir().unwrap();
+            let mut global_options = cli::GlobalOptions::default();
+            global_options.eval_cache = eval_cache;
+            let root = fake_nix(dir.path());
+            fs::write(root.join("bin/nix"), script).unwrap();
+            global_options.nix = Some(root);
+            let messages = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+            let sink_messages = messages.clone();
+            let logger =
+                log::Logger::new(log::Level::Silent).with_sink(std::sync::Arc::new(move |event| {
+                    if let log::Event::Log { message, .. } = event {
+                        sink_messages.lock().unwrap().push(message);
+                    }
+                }));
+            let nix = Nix::new(
+                logger,
+                config::Config::default(),
+                global_options,
+                dir.path().join("trusted-keys.json"),
+                dir.path().join("home-gc"),
+                dir.path().to_path_buf(),
+                dir.path().join("gc"),
+                dir.path().to_path_buf(),
+            )
+            .await
+            .unwrap();
+
+            let options = Options {
+                cache_output: true,
+                ..nix.options
+            };
+            let error = nix
+                .run_nix("nix", &["eval", ".#devenv.foo"], &options)
+                .await
+                .unwrap_err();
+            let error = error.downcast_ref::<NixError>().unwrap_or_else(|| {
+                panic!("not a NixError with eval_cache={eval_cache}: {error:?}")
+            });
+            assert_eq!(error.kind, NixErrorKind::UndefinedVariable);
+            assert_eq!(error.symbol.as_deref(), Some("foo"));
+            assert_eq!(
+                error.location.as_ref().map(|location| location.line),
+                Some(3)
+            );
+            // Nix's output went to the sink instead of stderr
+            assert!(
+                messages
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .any(|message| message.contains("undefined variable 'foo'")),
+                "nothing sent to the sink with eval_cache={eval_cache}"
+            );
+        }
+    }
+
+    fn fake_nix(dir: &Path) -> PathBuf {
+        use std::os::unix::fs::PermissionsExt;
+
+        let root = dir.join("nix");
+        fs::create_dir_all(root.join("bin")).unwrap();
+        fs::write(root.join("bin/nix"), "#!/bin/sh\n").unwrap();
+        fs::set_permissions(root.join("bin/nix"), fs::Permissions::from_mode(0o755)).unwrap();
+        root
+    }
+
+    #[test]
+    fn test_nix_root_precedence() {
+        let flag = Path::new("/flag");
+        let env = Path::new("/env");
+        assert_eq!(
+            nix_root(Some(flag), Some(env), Some("/config")),
+            Some(PathBuf::from("/flag"))
+        );
+        assert_eq!(
+            nix_root(None, Some(env), Some("/config")),
+            Some(PathBuf::from("/env"))
+        );
+        assert_eq!(
+            nix_root(None, None, Some("/config")),
+            Some(PathBuf::from("/config"))
+        );
+        assert_eq!(nix_root(None, Some(Path::new("")), None), None);
+        assert_eq!(nix_root(None, None, None), None);
+    }
+
+    #[test]
+    fn test_check_nix() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let root = fake_nix(dir.path());
+        assert!(check_nix(&root).is_ok());
+
+        let err = check_nix(&dir.path().join("missing")).unwrap_err();
+        assert!(err.to_string().contains("missing/bin/nix"));
+
+        fs::set_permissions(root.join("bin/nix"), fs::Permissions::from_mode(0o644)).unwrap();
+        let err = check_nix(&root).unwrap_err();
+        assert!(err.to_string().ends_with("is not an executable file"));
+    }
+
+    #[test]
+    fn test_parse_why_depends() {
+        let output = indoc::indoc! {"
+            /nix/store/l6qk2dm3kxw8hvbbn4dzqnm6ahbd3s3p-devenv-profile
+            └───/nix/store/5y3gdbp7w9m6d9nfbvkx2h8rh9m1y1hf-python3-3.11.9
+                └───/nix/store/0m7gbkhk7q9zss3i2mw0hnfq0myc2fxb-openssl-3.0.13
+        "};
+        assert_eq!(
+            parse_why_depends(output),
+            vec![
+                PathBuf::from("/nix/store/l6qk2dm3kxw8hvbbn4dzqnm6ahbd3s3p-devenv-profile"),
+                PathBuf::from("/nix/store/5y3gdbp7w9m6d9nfbvkx2h8rh9m1y1hf-python3-3.11.9"),
+                PathBuf::from("/nix/store/0m7gbkhk7q9zss3i2mw0hnfq0myc2fxb-openssl-3.0.13"),
+            ]
+        );
+        assert!(parse_why_depends("").is_empty());
+    }
+
+    #[test]
+    fn test_parse_missing_attribute() {
+        let stderr = indoc::indoc! {"
+            error:
+                   … while evaluating the attribute 'devenv.shell'
+                     at /nix/store/0dcwz4i5rpd1qf2f0fh1bx8ih5xg2pvj-source/flake.nix:102:11:
+
+                   error: attribute 'pyhton3' missing
+                   at /home/user/project/devenv.nix:4:5:
+                        3|   packages = [
+                        4|     pkgs.pyhton3
+                         |     ^
+                        5|   ];
+        "};
+        let error = parse_nix_error(stderr).unwrap();
+        assert_eq!(error.kind, NixErrorKind::MissingAttribute);
+        assert_eq!(error.symbol.as_deref(), Some("pyhton3"));
+        assert_eq!(
+            error.location,
+            Some(NixErrorLocation {
+                file: "/home/user/project/devenv.nix".to_string(),
+                line: 4,
+                column: 5,
+            })
+        );
+        assert_eq!(error.text, stderr);
+        assert_eq!(
+            error.to_string(),
+            "attribute 'pyhton3' missing at /home/user/project/devenv.nix:4:5"
+        );
+    }
+
+    #[test]
+    fn test_parse_undefined_variable() {
+        let stderr = indoc::indoc! {"
+            error: undefined variable 'lib'
+                   at /home/user/project/devenv.nix:7:16:
+                        6|
+                        7|   env.PATHS = lib.makeBinPath [ ];
+                         |                ^
+        "};
+        let error = parse_nix_error(stderr).unwrap();
+        assert_eq!(error.kind, NixErrorKind::UndefinedVariable);
+        assert_eq!(error.symbol.as_deref(), Some("lib"));
+        assert_eq!(error.location.unwrap().line, 7);
+        assert!(error.hint.unwrap().contains("{ pkgs, lib, ... }:"));
+    }
+
+    #[test]
+    fn test_parse_syntax_error() {
+        let stderr = "error: syntax error, unexpected '}', expecting ';', at /home/user/project/devenv.nix:9:1\n";
+        let error = parse_nix_error(stderr).unwrap();
+        assert_eq!(error.kind, NixErrorKind::SyntaxError);
+        assert_eq!(error.message, "syntax error, unexpected '}', expecting ';'");
+        assert_eq!(error.symbol, None);
+        assert_eq!(
+            error.location,
+            Some(NixErrorLocation {
+                file: "/home/user/project/devenv.nix".to_string(),
+                line: 9,
+                column: 1,
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_unknown_error() {
+        assert!(parse_nix_error(
+            "error: cannot connect to socket at '/nix/var/nix/daemon-socket/socket'"
+        )
+        .is_none());
+        assert!(parse_nix_error("").is_none());
+    }
+
     #[test]
     fn test_trusted() {
         let store_ping = r#"{"trusted":1,"url":"daemon","version":"2.18.1"}"#;
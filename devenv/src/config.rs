@@ -1,10 +1,18 @@
 use miette::{IntoDiagnostic, Result};
-use schemars::{schema_for, JsonSchema};
-use schematic::ConfigLoader;
+use schemars::{
+    schema::{InstanceType, RootSchema, Schema, SchemaObject, SingleOrVec},
+    schema_for, JsonSchema,
+};
+use schematic::{ConfigLoader, Format};
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, fmt, path::Path};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    path::{Path, PathBuf},
+};
 
 const YAML_CONFIG: &str = "devenv.yaml";
+const YAML_LOCAL_CONFIG: &str = "devenv.local.yaml";
 
 #[derive(schematic::Config, Clone, Debug, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
 #[config(rename_all = "camelCase")]
@@ -79,6 +87,577 @@ impl TryFrom<&Input> for FlakeInput {
     }
 }
 
+/// An input as declared in devenv.yaml, combined with what devenv.lock knows about it.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct InputStatus {
+    pub name: String,
+    pub url: Option<String>,
+    /// Fetcher type from the lock, e.g. `github`, `path` or `tarball`.
+    #[serde(rename = "type")]
+    pub type_: Option<String>,
+    pub rev: Option<String>,
+    /// The input this one follows instead of being fetched itself.
+    pub follows: Option<String>,
+    /// Inputs of this input that follow another input, e.g. `nixpkgs -> nixpkgs`.
+    pub inputs_follow: BTreeMap<String, String>,
+    pub store_path: Option<PathBuf>,
+    pub fetched: bool,
+}
+
+/// List every input of the project, using the root node of `lock` (the contents of devenv.lock)
+/// to fill in the locked type and revision.
+///
+/// Store paths are filled in separately, as they require asking Nix.
+pub fn input_statuses(config: &Config, lock: Option<&str>) -> Result<Vec<InputStatus>> {
+    let lock: serde_json::Value = match lock {
+        Some(lock) => serde_json::from_str(lock).into_diagnostic()?,
+        None => serde_json::Value::Null,
+    };
+    let nodes = &lock["nodes"];
+    let root = lock["root"].as_str().unwrap_or("root");
+    let root_inputs = nodes[root]["inputs"].as_object();
+
+    let mut names = config.inputs.keys().cloned().collect::<Vec<_>>();
+    if let Some(root_inputs) = root_inputs {
+        names.extend(root_inputs.keys().cloned());
+    }
+    names.sort();
+    names.dedup();
+
+    let statuses = names
+        .into_iter()
+        .map(|name| {
+            let input = config.inputs.get(&name);
+            let locked_node = root_inputs.and_then(|inputs| inputs.get(&name));
+            let (node, follows) = match locked_node {
+                Some(serde_json::Value::String(node)) => (nodes.get(node), None),
+                // follows are recorded as a path of input names
+                Some(serde_json::Value::Array(path)) => (
+                    None,
+                    Some(
+                        path.iter()
+                            .filter_map(|p| p.as_str())
+                            .collect::<Vec<_>>()
+                            .join("/"),
+                    ),
+                ),
+                _ => (None, None),
+            };
+            let locked = node.map(|node| &node["locked"]);
+            InputStatus {
+                url: input.and_then(|i| i.url.clone()),
+                type_: locked.and_then(|l| l["type"].as_str()).map(String::from),
+                rev: locked.and_then(|l| l["rev"].as_str()).map(String::from),
+                follows: follows.or_else(|| input.and_then(|i| i.follows.clone())),
+                inputs_follow: input
+                    .map(|i| {
+                        i.inputs
+                            .iter()
+                            .filter_map(|(sub, sub_input)| {
+                                sub_input.follows.clone().map(|f| (sub.clone(), f))
+                            })
+                            .collect()
+                    })
+                    .unwrap_or_default(),
+                store_path: None,
+                fetched: false,
+                name,
+            }
+        })
+        .collect();
+    Ok(statuses)
+}
+
+impl InputStatus {
+    /// The url to look for newer revisions at, for inputs that are locked to a revision themselves.
+    pub fn upstream_url(&self) -> Option<&str> {
+        match (&self.url, &self.rev, &self.follows) {
+            (Some(url), Some(_), None) => Some(url),
+            _ => None,
+        }
+    }
+}
+
+/// How an input's locked revision compares to the newest one upstream.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct InputUpdate {
+    pub name: String,
+    pub url: String,
+    pub locked_rev: String,
+    /// `None` if upstream couldn't be queried.
+    pub latest_rev: Option<String>,
+    pub outdated: bool,
+}
+
+/// Compare the locked revision of every input that has an [`InputStatus::upstream_url`]
+/// against `latest`, the newest revision found at each url.
+pub fn input_updates(inputs: &[InputStatus], latest: &HashMap<String, String>) -> Vec<InputUpdate> {
+    inputs
+        .iter()
+        .filter_map(|input| {
+            let url = input.upstream_url()?;
+            let locked_rev = input.rev.clone()?;
+            let latest_rev = latest.get(url).cloned();
+            Some(InputUpdate {
+                name: input.name.clone(),
+                url: url.to_string(),
+                outdated: latest_rev
+                    .as_ref()
+                    .is_some_and(|latest| *latest != locked_rev),
+                locked_rev,
+                latest_rev,
+            })
+        })
+        .collect()
+}
+
+/// Deep merge `local` over `base`: mappings are merged key by key,
+/// anything else in `local`, including lists, replaces what is in `base`.
+fn merge_yaml(base: &mut serde_yaml::Value, local: serde_yaml::Value) {
+    match (base, local) {
+        (serde_yaml::Value::Mapping(base), serde_yaml::Value::Mapping(local)) => {
+            for (key, value) in local {
+                match base.get_mut(&key) {
+                    Some(base_value) => merge_yaml(base_value, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        // An empty local file doesn't override anything
+        (_, serde_yaml::Value::Null) => {}
+        (base, local) => *base = local,
+    }
+}
+
+/// A mistake in a devenv.yaml file, pointing at the line and column it's at.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ConfigProblem {
+    pub file: PathBuf,
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let file = self.file.strip_prefix(".").unwrap_or(&self.file);
+        write!(
+            f,
+            "{}:{}:{}: {}",
+            file.display(),
+            self.line,
+            self.column,
+            self.message
+        )
+    }
+}
+
+impl ConfigProblem {
+    /// Format the problem as a GitHub Actions `::error` workflow command,
+    /// which shows it inline on the offending line of a pull request.
+    pub fn github_annotation(&self) -> String {
+        let file = self.file.strip_prefix(".").unwrap_or(&self.file);
+        format!(
+            "::error file={},line={},col={}::{}",
+            escape_github_property(&file.to_string_lossy()),
+            self.line,
+            self.column,
+            escape_github_data(&self.message)
+        )
+    }
+}
+
+fn escape_github_data(s: &str) -> String {
+    s.replace('%', "%25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+fn escape_github_property(s: &str) -> String {
+    escape_github_data(s)
+        .replace(':', "%3A")
+        .replace(',', "%2C")
+}
+
+/// Every problem found in a configuration file, reported together.
+#[derive(Debug, thiserror::Error, miette::Diagnostic)]
+#[error("Invalid configuration:\n{}", .problems.iter().map(|p| p.to_string()).collect::<Vec<_>>().join("\n"))]
+pub struct ConfigError {
+    pub problems: Vec<ConfigProblem>,
+}
+
+/// Settings that make sense in the `nixConfig` of the generated flake.
+const NIX_CONFIG_FIELDS: [&str; 16] = [
+    "allow-import-from-derivation",
+    "bash-prompt",
+    "bash-prompt-prefix",
+    "bash-prompt-suffix",
+    "commit-lock-file-summary",
+    "experimental-features",
+    "extra-experimental-features",
+    "extra-substituters",
+    "extra-trusted-public-keys",
+    "extra-trusted-substituters",
+    "flake-registry",
+    "keep-derivations",
+    "keep-outputs",
+    "substituters",
+    "trusted-public-keys",
+    "trusted-substituters",
+];
+
+/// Check the contents of a devenv.yaml file for unknown fields, values of the wrong type
+/// and malformed inputs, without stopping at the first problem.
+///
+/// The known fields and their types are taken from the JSON schema of [`Config`].
+pub fn validate(file: &Path, source: &str) -> Vec<ConfigProblem> {
+    let schema = schema_for!(Config);
+    let mut validator = Validator {
+        file,
+        lines: source.lines().collect(),
+        problems: vec![],
+        schema: &schema,
+    };
+    match serde_yaml::from_str::<serde_yaml::Value>(source) {
+        Ok(value) => validator.config(&value),
+        Err(e) => {
+            let (line, column) = e
+                .location()
+                .map(|l| (l.line(), l.column()))
+                .unwrap_or((1, 1));
+            validator.problems.push(ConfigProblem {
+                file: file.to_path_buf(),
+                line,
+                column,
+                message: e.to_string(),
+            });
+        }
+    }
+    validator.problems.sort_by_key(|p| (p.line, p.column));
+    validator.problems
+}
+
+struct Validator<'a> {
+    file: &'a Path,
+    lines: Vec<&'a str>,
+    problems: Vec<ConfigProblem>,
+    schema: &'a RootSchema,
+}
+
+impl<'a> Validator<'a> {
+    fn config(&mut self, value: &serde_yaml::Value) {
+        match value {
+            serde_yaml::Value::Null => {}
+            serde_yaml::Value::Mapping(_) => {
+                let schema = self.schema;
+                self.value(value, &schema.schema, &[])
+            }
+            _ => self.report(&[], "expected a mapping of settings".to_string()),
+        }
+    }
+
+    /// Check `value` against `schema`, descending into mappings and lists.
+    fn value(&mut self, value: &serde_yaml::Value, schema: &'a SchemaObject, path: &[&str]) {
+        let is_input = schema.reference.as_deref() == Some("#/definitions/Input");
+        let Some(schema) = self.resolve(schema, value) else {
+            return;
+        };
+        match single_type(schema) {
+            Some(InstanceType::Object) => {
+                let Some(fields) = value.as_mapping() else {
+                    return self.expected(path, "a mapping");
+                };
+                let object = schema.object.as_deref();
+                match object.map(|object| &object.properties) {
+                    Some(properties) if !properties.is_empty() => {
+                        let known = properties.keys().map(String::as_str).collect::<Vec<_>>();
+                        for (key, value) in self.fields(fields, path, &known) {
+                            if let Schema::Object(property) = &properties[&key] {
+                                self.value(value, property, &[path, &[key.as_str()]].concat());
+                            }
+                        }
+                    }
+                    _ => {
+                        let Some(Schema::Object(item)) =
+                            object.and_then(|object| object.additional_properties.as_deref())
+                        else {
+                            return;
+                        };
+                        // Nix settings are free-form in the schema,
+                        // but only some of them make sense for the generated flake
+                        let known = if path == ["nixConfig"] {
+                            NIX_CONFIG_FIELDS.to_vec()
+                        } else {
+                            fields.keys().filter_map(|key| key.as_str()).collect()
+                        };
+                        for (key, value) in self.fields(fields, path, &known) {
+                            self.value(value, item, &[path, &[key.as_str()]].concat());
+                        }
+                    }
+                }
+                if is_input && fields.contains_key("url") && fields.contains_key("follows") {
+                    self.report(path, FlakeInputError::UrlAndFollowsBothSet.to_string());
+                }
+            }
+            Some(InstanceType::Array) => {
+                let items = match schema.array.as_ref().and_then(|array| array.items.as_ref()) {
+                    Some(SingleOrVec::Single(items)) => match items.as_ref() {
+                        Schema::Object(items) => Some(items),
+                        Schema::Bool(_) => None,
+                    },
+                    _ => None,
+                };
+                let matches = value.as_sequence().is_some_and(|values| {
+                    values
+                        .iter()
+                        .all(|value| items.is_none_or(|items| has_type(value, single_type(items))))
+                });
+                if !matches {
+                    let of_strings = items.and_then(single_type) == Some(InstanceType::String);
+                    self.expected(
+                        path,
+                        if of_strings {
+                            "a list of strings"
+                        } else {
+                            "a list"
+                        },
+                    );
+                }
+            }
+            Some(InstanceType::Boolean) if !value.is_bool() => self.expected(path, "true or false"),
+            Some(InstanceType::String) => match value.as_str() {
+                Some(system) if path == ["system"] && !crate::cli::SYSTEMS.contains(&system) => {
+                    let message = crate::cli::parse_system(system).unwrap_err();
+                    self.report(path, message)
+                }
+                Some(_) => {}
+                None => self.expected(path, "a string"),
+            },
+            _ => {}
+        }
+    }
+
+    /// Follow references into the schema's definitions and pick the non-null type of optional values.
+    /// Returns `None` if `value` is a null that the schema allows.
+    fn resolve(
+        &self,
+        schema: &'a SchemaObject,
+        value: &serde_yaml::Value,
+    ) -> Option<&'a SchemaObject> {
+        if let Some(reference) = &schema.reference {
+            let name = reference.strip_prefix("#/definitions/")?;
+            return match self.schema.definitions.get(name)? {
+                Schema::Object(definition) => self.resolve(definition, value),
+                Schema::Bool(_) => None,
+            };
+        }
+        // Optional values are any of the value's own schema and `null`
+        if let Some(any_of) = schema.subschemas.as_ref().and_then(|s| s.any_of.as_ref()) {
+            let mut alternatives = any_of.iter().filter_map(|alternative| match alternative {
+                Schema::Object(alternative) => Some(alternative),
+                Schema::Bool(_) => None,
+            });
+            if value.is_null() && alternatives.clone().any(allows_null) {
+                return None;
+            }
+            return alternatives
+                .find(|alternative| !allows_null(alternative))
+                .and_then(|alternative| self.resolve(alternative, value));
+        }
+        if value.is_null() && allows_null(schema) {
+            return None;
+        }
+        Some(schema)
+    }
+
+    /// Report unknown fields and return the known ones.
+    fn fields<'v>(
+        &mut self,
+        fields: &'v serde_yaml::Mapping,
+        path: &[&str],
+        known: &[&str],
+    ) -> Vec<(String, &'v serde_yaml::Value)> {
+        let mut found = vec![];
+        for (key, value) in fields {
+            let Some(key) = key.as_str() else {
+                self.report(path, "field names must be strings".to_string());
+                continue;
+            };
+            if known.contains(&key) {
+                found.push((key.to_string(), value));
+                continue;
+            }
+            let path = [path, &[key]].concat();
+            let hint = match closest_fields(key, known).as_slice() {
+                [] => format!(
+                    "expected one of {}",
+                    known
+                        .iter()
+                        .map(|k| format!("'{k}'"))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+                [closest @ .., last] => {
+                    let closest = closest
+                        .iter()
+                        .map(|field| format!("'{field}'"))
+                        .collect::<Vec<_>>();
+                    if closest.is_empty() {
+                        format!("did you mean '{last}'?")
+                    } else {
+                        format!("did you mean {} or '{last}'?", closest.join(", "))
+                    }
+                }
+            };
+            self.report(&path, format!("unknown field '{}', {hint}", path.join(".")));
+        }
+        found
+    }
+
+    fn expected(&mut self, path: &[&str], what: &str) {
+        self.report(path, format!("'{}' should be {what}", path.join(".")));
+    }
+
+    fn report(&mut self, path: &[&str], message: String) {
+        let (line, column) = self.locate(path);
+        self.problems.push(ConfigProblem {
+            file: self.file.to_path_buf(),
+            line,
+            column,
+            message,
+        });
+    }
+
+    /// Find the line and column of the key at `path`, both starting at 1,
+    /// by following the indentation of block mappings.
+    /// Falls back to the closest parent that could be found.
+    fn locate(&self, path: &[&str]) -> (usize, usize) {
+        let mut location = (1, 1);
+        let mut start = 0;
+        let mut min_indent = 0;
+        'keys: for key in path {
+            for (index, line) in self.lines.iter().enumerate().skip(start) {
+                let content = line.trim_start();
+                if content.is_empty() || content.starts_with('#') {
+                    continue;
+                }
+                let indent = line.len() - content.len();
+                if indent < min_indent {
+                    break;
+                }
+                let name = content.split_once(':').map(|(name, _)| name.trim_end());
+                let matches = name.is_some_and(|name| {
+                    name == *key || name.trim_matches(|c| c == '"' || c == '\'') == *key
+                });
+                if matches {
+                    location = (index + 1, indent + 1);
+                    start = index + 1;
+                    min_indent = indent + 1;
+                    continue 'keys;
+                }
+            }
+            break;
+        }
+        location
+    }
+}
+
+/// The type of values matching `schema`, leaving out `null`.
+fn single_type(schema: &SchemaObject) -> Option<InstanceType> {
+    match schema.instance_type.as_ref()? {
+        SingleOrVec::Single(instance_type) => Some(**instance_type),
+        SingleOrVec::Vec(instance_types) => instance_types
+            .iter()
+            .copied()
+            .find(|instance_type| *instance_type != InstanceType::Null),
+    }
+}
+
+fn allows_null(schema: &SchemaObject) -> bool {
+    match &schema.instance_type {
+        Some(SingleOrVec::Single(instance_type)) => **instance_type == InstanceType::Null,
+        Some(SingleOrVec::Vec(instance_types)) => instance_types.contains(&InstanceType::Null),
+        None => false,
+    }
+}
+
+/// Whether `value` is of `instance_type`, without looking inside it.
+fn has_type(value: &serde_yaml::Value, instance_type: Option<InstanceType>) -> bool {
+    match instance_type {
+        Some(InstanceType::Boolean) => value.is_bool(),
+        Some(InstanceType::String) => value.is_string(),
+        Some(InstanceType::Array) => value.is_sequence(),
+        Some(InstanceType::Object) => value.is_mapping(),
+        _ => true,
+    }
+}
+
+/// The known fields closest to a mistyped `key`, if any is close enough to be a typo.
+/// Several are returned when they are equally close.
+fn closest_fields<'a>(key: &str, known: &[&'a str]) -> Vec<&'a str> {
+    let distances = known
+        .iter()
+        .map(|field| {
+            (
+                edit_distance(&key.to_lowercase(), &field.to_lowercase()),
+                *field,
+            )
+        })
+        .filter(|(distance, _)| *distance <= 2)
+        .collect::<Vec<_>>();
+    let Some(closest) = distances.iter().map(|(distance, _)| *distance).min() else {
+        return vec![];
+    };
+    distances
+        .into_iter()
+        .filter(|(distance, _)| *distance == closest)
+        .map(|(_, field)| field)
+        .collect()
+}
+
+/// Levenshtein distance between `a` and `b`, counted in characters.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut previous = (0..=b.len()).collect::<Vec<_>>();
+    for (i, a) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a != *b);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// Validate devenv.yaml and devenv.local.yaml in `path`, skipping the ones that don't exist.
+pub fn check<P: AsRef<Path>>(path: P) -> Vec<ConfigProblem> {
+    [YAML_CONFIG, YAML_LOCAL_CONFIG]
+        .iter()
+        .map(|name| path.as_ref().join(name))
+        .filter_map(|file| {
+            let source = std::fs::read_to_string(&file).ok()?;
+            Some(validate(&file, &source))
+        })
+        .flatten()
+        .collect()
+}
+
+/// Validate `file` if it exists, see [`validate`].
+fn check_file(file: &Path) -> Result<()> {
+    let Ok(source) = std::fs::read_to_string(file) else {
+        return Ok(());
+    };
+    let problems = validate(file, &source);
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(ConfigError { problems }.into())
+    }
+}
+
 fn true_default() -> bool {
     true
 }
@@ -121,6 +700,12 @@ pub struct Config {
     pub clean: Option<Clean>,
     #[serde(skip_serializing_if = "is_false", default = "false_default")]
     pub impure: bool,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub nix: Option<String>,
+    #[serde(skip_serializing_if = "HashMap::is_empty", default)]
+    pub nix_config: HashMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub system: Option<String>,
 }
 
 // TODO: https://github.com/moonrepo/schematic/issues/105
@@ -137,11 +722,46 @@ impl Config {
         Self::load_from("./")
     }
 
+    /// Load devenv.yaml from `path`, with devenv.local.yaml merged over it if it exists.
     pub fn load_from<P>(path: P) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let local = path.as_ref().join(YAML_LOCAL_CONFIG);
+        if !local.exists() {
+            return Self::load_shared_from(path);
+        }
+        check_file(&path.as_ref().join(YAML_CONFIG))?;
+        check_file(&local)?;
+
+        let read_yaml = |file: &Path| -> Result<serde_yaml::Value> {
+            match std::fs::read_to_string(file) {
+                Ok(contents) => serde_yaml::from_str(&contents)
+                    .into_diagnostic()
+                    .map_err(|e| e.wrap_err(format!("Failed to parse {}", file.display()))),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(serde_yaml::Value::Null),
+                Err(e) => Err(e).into_diagnostic(),
+            }
+        };
+        let mut config = read_yaml(&path.as_ref().join(YAML_CONFIG))?;
+        merge_yaml(&mut config, read_yaml(&local)?);
+
+        let mut loader = ConfigLoader::<Config>::new();
+        if !config.is_null() {
+            let code = serde_yaml::to_string(&config).into_diagnostic()?;
+            loader.code(code, Format::Yaml).into_diagnostic()?;
+        }
+        let result = loader.load().into_diagnostic();
+        Ok(result?.config)
+    }
+
+    /// Load only devenv.yaml from `path`, which is shared with everyone working on the project.
+    pub fn load_shared_from<P>(path: P) -> Result<Self>
     where
         P: AsRef<Path>,
     {
         let file = path.as_ref().join(YAML_CONFIG);
+        check_file(&file)?;
         let mut loader = ConfigLoader::<Config>::new();
         let _ = loader.file_optional(file);
         let result = loader.load().into_diagnostic();
@@ -195,4 +815,303 @@ mod tests {
         assert!(result.is_err());
         assert_eq!(result.unwrap_err(), FlakeInputError::UrlAndFollowsBothSet);
     }
+
+    #[test]
+    fn input_statuses_from_yaml_and_lock() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dir.path().join(YAML_CONFIG),
+            indoc::indoc! {"
+            inputs:
+              nixpkgs:
+                url: github:cachix/devenv-nixpkgs/rolling
+              local:
+                url: path:./local
+                inputs:
+                  nixpkgs:
+                    follows: nixpkgs
+              unlocked:
+                url: https://example.com/source.tar.gz
+            "},
+        )
+        .unwrap();
+        let lock = serde_json::json!({
+            "nodes": {
+                "root": {
+                    "inputs": {
+                        "local": "local",
+                        "nixpkgs": "nixpkgs",
+                        "nixpkgs-stable": ["local", "nixpkgs"]
+                    }
+                },
+                "local": {
+                    "locked": { "path": "./local", "type": "path" }
+                },
+                "nixpkgs": {
+                    "locked": {
+                        "owner": "cachix",
+                        "repo": "devenv-nixpkgs",
+                        "rev": "abc123",
+                        "type": "github"
+                    }
+                }
+            },
+            "root": "root",
+            "version": 7
+        })
+        .to_string();
+
+        let config = Config::load_from(dir.path()).unwrap();
+        let statuses = input_statuses(&config, Some(&lock)).unwrap();
+        let summary = statuses
+            .iter()
+            .map(|s| {
+                (
+                    s.name.as_str(),
+                    s.type_.as_deref(),
+                    s.rev.as_deref(),
+                    s.follows.as_deref(),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            summary,
+            vec![
+                ("local", Some("path"), None, None),
+                ("nixpkgs", Some("github"), Some("abc123"), None),
+                ("nixpkgs-stable", None, None, Some("local/nixpkgs")),
+                ("unlocked", None, None, None),
+            ]
+        );
+        assert_eq!(
+            statuses[0].inputs_follow,
+            BTreeMap::from([("nixpkgs".to_string(), "nixpkgs".to_string())])
+        );
+        assert_eq!(
+            statuses[3].url.as_deref(),
+            Some("https://example.com/source.tar.gz")
+        );
+    }
+
+    #[test]
+    fn local_config_is_merged_over_the_shared_one() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dir.path().join(YAML_CONFIG),
+            indoc::indoc! {"
+            allowUnfree: false
+            imports:
+              - ./frontend
+              - ./backend
+            inputs:
+              nixpkgs:
+                url: github:cachix/devenv-nixpkgs/rolling
+              tools:
+                url: github:example/tools
+                inputs:
+                  nixpkgs:
+                    follows: nixpkgs
+            "},
+        )
+        .unwrap();
+
+        // Without a local file, only the shared one is loaded
+        let config = Config::load_from(dir.path()).unwrap();
+        assert_eq!(config.imports, ["./frontend", "./backend"]);
+        assert!(!config.allow_unfree);
+
+        std::fs::write(
+            dir.path().join(YAML_LOCAL_CONFIG),
+            indoc::indoc! {"
+            allowUnfree: true
+            imports:
+              - ./backend
+            inputs:
+              tools:
+                url: path:/home/me/tools
+            "},
+        )
+        .unwrap();
+
+        let config = Config::load_from(dir.path()).unwrap();
+        assert!(config.allow_unfree);
+        // Lists are replaced
+        assert_eq!(config.imports, ["./backend"]);
+        // Maps are merged recursively
+        assert_eq!(
+            config.inputs["nixpkgs"].url.as_deref(),
+            Some("github:cachix/devenv-nixpkgs/rolling")
+        );
+        let tools = &config.inputs["tools"];
+        assert_eq!(tools.url.as_deref(), Some("path:/home/me/tools"));
+        assert_eq!(tools.inputs["nixpkgs"].follows.as_deref(), Some("nixpkgs"));
+
+        let shared = Config::load_shared_from(dir.path()).unwrap();
+        assert!(!shared.allow_unfree);
+        assert_eq!(
+            shared.inputs["tools"].url.as_deref(),
+            Some("github:example/tools")
+        );
+    }
+
+    #[test]
+    fn input_updates_against_upstream() {
+        let input =
+            |name: &str, url: Option<&str>, rev: Option<&str>, follows: Option<&str>| InputStatus {
+                name: name.to_string(),
+                url: url.map(String::from),
+                type_: None,
+                rev: rev.map(String::from),
+                follows: follows.map(String::from),
+                inputs_follow: BTreeMap::new(),
+                store_path: None,
+                fetched: false,
+            };
+        let inputs = [
+            input("current", Some("github:a/current"), Some("aaa"), None),
+            input("outdated", Some("github:a/outdated"), Some("bbb"), None),
+            input("offline", Some("github:a/offline"), Some("ccc"), None),
+            input("follower", None, None, Some("current")),
+            input("unlocked", Some("path:./local"), None, None),
+        ];
+        // What upstream reports as the newest revisions
+        let upstream = HashMap::from([
+            ("github:a/current".to_string(), "aaa".to_string()),
+            ("github:a/outdated".to_string(), "bbb2".to_string()),
+        ]);
+
+        let updates = input_updates(&inputs, &upstream);
+        let summary = updates
+            .iter()
+            .map(|u| (u.name.as_str(), u.latest_rev.as_deref(), u.outdated))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            vec![
+                ("current", Some("aaa"), false),
+                ("outdated", Some("bbb2"), true),
+                ("offline", None, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn validate_reports_every_problem_with_its_location() {
+        let source = indoc::indoc! {"
+            # project settings
+            allowUnfree: yes please
+            imput:
+              nixpkgs:
+                url: github:cachix/devenv-nixpkgs/rolling
+            inputs:
+              nixpkgs:
+                url: github:cachix/devenv-nixpkgs/rolling
+                follows: other
+              tools:
+                url: github:example/tools
+                flake: 1
+                inputs:
+                  nixpkgs:
+                    folows: nixpkgs
+            clean:
+              enabled: true
+              keep: PATH
+            "};
+        let problems = validate(Path::new("./devenv.yaml"), source)
+            .iter()
+            .map(|p| p.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            problems,
+            [
+                "devenv.yaml:2:1: 'allowUnfree' should be true or false",
+                "devenv.yaml:3:1: unknown field 'imput', did you mean 'impure' or 'inputs'?",
+                "devenv.yaml:7:3: url and follows cannot both be set for the same input",
+                "devenv.yaml:12:5: 'inputs.tools.flake' should be true or false",
+                "devenv.yaml:15:9: unknown field 'inputs.tools.inputs.nixpkgs.folows', did you mean 'follows'?",
+                "devenv.yaml:18:3: 'clean.keep' should be a list of strings",
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_fields_without_a_close_match_list_the_expected_ones() {
+        let problems = validate(Path::new("devenv.yaml"), "clean:\n  packages: []\n");
+        assert_eq!(
+            problems[0].to_string(),
+            "devenv.yaml:2:3: unknown field 'clean.packages', expected one of 'enabled', 'keep'"
+        );
+    }
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(edit_distance("imput", "inputs"), 2);
+        assert_eq!(edit_distance("follows", "follows"), 0);
+        assert_eq!(edit_distance("", "url"), 3);
+        let fields = ["allowBroken", "allowUnfree", "impure", "inputs"];
+        assert_eq!(closest_fields("alowUnfree", &fields), ["allowUnfree"]);
+        assert_eq!(closest_fields("imput", &fields), ["impure", "inputs"]);
+        assert!(closest_fields("packages", &fields).is_empty());
+    }
+
+    #[test]
+    fn validate_nix_config() {
+        let source = indoc::indoc! {"
+            nixConfig:
+              extra-substituters: https://example.cachix.org
+              extra-experimental-feature: pipe-operators
+              keep-outputs: true
+            "};
+        let problems = validate(Path::new("devenv.yaml"), source)
+            .iter()
+            .map(|p| p.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            problems,
+            [
+                "devenv.yaml:3:3: unknown field 'nixConfig.extra-experimental-feature', did you mean 'extra-experimental-features'?",
+                "devenv.yaml:4:3: 'nixConfig.keep-outputs' should be a string",
+            ]
+        );
+    }
+
+    #[test]
+    fn validate_system() {
+        let problems = validate(Path::new("devenv.yaml"), "system: x86_64-windows\n");
+        assert_eq!(
+            problems[0].to_string(),
+            "devenv.yaml:1:1: `x86_64-windows` is not a supported system, expected one of x86_64-linux, aarch64-linux, x86_64-darwin, aarch64-darwin"
+        );
+        assert!(validate(Path::new("devenv.yaml"), "system: aarch64-linux\n").is_empty());
+    }
+
+    #[test]
+    fn github_annotations() {
+        let problem = ConfigProblem {
+            file: PathBuf::from("./sub,dir/devenv.yaml"),
+            line: 3,
+            column: 1,
+            message: "unknown field 'imput', did you mean 'inputs'?\n100% sure".to_string(),
+        };
+        assert_eq!(
+            problem.github_annotation(),
+            "::error file=sub%2Cdir/devenv.yaml,line=3,col=1::unknown field 'imput', did you mean 'inputs'?%0A100%25 sure"
+        );
+    }
+
+    #[test]
+    fn validate_reports_syntax_errors() {
+        let problems = validate(Path::new("devenv.yaml"), "inputs:\n  nixpkgs: [\n");
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].to_string().starts_with("devenv.yaml:"));
+    }
+
+    #[test]
+    fn load_fails_on_invalid_config() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join(YAML_CONFIG), "imput: {}\n").unwrap();
+        let err = Config::load_from(dir.path()).unwrap_err();
+        assert!(err.to_string().contains("1:1: unknown field 'imput'"));
+    }
 }
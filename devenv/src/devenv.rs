@@ -6,9 +6,9 @@ use include_dir::{include_dir, Dir};
 use miette::{bail, Result};
 use nix::sys::signal;
 use nix::unistd::Pid;
-use serde::Deserialize;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use sha2::Digest;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::io::Write;
 use std::os::unix::{fs::PermissionsExt, process::CommandExt};
 use std::{
@@ -24,6 +24,139 @@ const PROJECT_DIR: Dir = include_dir!("$CARGO_MANIFEST_DIR/init");
 // project vars
 const DEVENV_FLAKE: &str = ".devenv.flake.nix";
 
+/// When set, entering the shell skips the greeting and hints, e.g. for use in scripts.
+pub const NO_BANNER_ENV: &str = "DEVENV_NO_BANNER";
+
+#[derive(Debug, thiserror::Error, miette::Diagnostic)]
+pub enum EvalError {
+    #[error("Failed to evaluate {attribute}: {message}")]
+    Nix { attribute: String, message: String },
+    #[error("Failed to deserialize {attribute}")]
+    Deserialize {
+        attribute: String,
+        #[source]
+        source: serde_json::Error,
+    },
+}
+
+#[derive(Debug, thiserror::Error, miette::Diagnostic)]
+pub enum BuildError {
+    /// Nix failed with an error recognized from its output.
+    #[error("Failed to build {attribute}")]
+    Nix {
+        attribute: String,
+        #[source]
+        #[diagnostic_source]
+        source: crate::NixError,
+    },
+    /// Nix couldn't be run or failed in a way that isn't recognized.
+    #[error("Failed to build {attribute}: {message}")]
+    Command { attribute: String, message: String },
+    #[error("Building {attribute} produced no output")]
+    NoOutput { attribute: String },
+}
+
+/// State under `.devenv` that `devenv clean` can remove selectively.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StateCategory {
+    /// Process logs and the full output of tasks.
+    Logs,
+    /// The cache of Nix evaluations.
+    EvalCache,
+    /// What cached tasks remember about their inputs.
+    TaskState,
+}
+
+impl StateCategory {
+    pub const ALL: [StateCategory; 3] = [Self::Logs, Self::EvalCache, Self::TaskState];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Logs => "logs",
+            Self::EvalCache => "evaluation cache",
+            Self::TaskState => "task state",
+        }
+    }
+
+    /// The files and directories holding this category, relative to `dotfile`.
+    fn paths(&self, dotfile: &Path) -> Vec<PathBuf> {
+        let tasks_dir = dotfile.join("state").join("tasks");
+        match self {
+            Self::Logs => vec![dotfile.join("processes.log"), tasks_dir.join("logs")],
+            Self::EvalCache => [
+                "nix-eval-cache.db",
+                "nix-eval-cache.db-wal",
+                "nix-eval-cache.db-shm",
+            ]
+            .iter()
+            .map(|name| dotfile.join(name))
+            .collect(),
+            Self::TaskState => fs::read_dir(&tasks_dir)
+                .into_iter()
+                .flatten()
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|path| path.file_name() != Some("logs".as_ref()))
+                .collect(),
+        }
+    }
+}
+
+/// Options for [`Devenv::tasks_run`] besides the tasks to run.
+#[derive(Default)]
+pub struct TasksRunOptions {
+    /// Extra environment variables set for every task.
+    pub env: Vec<(String, String)>,
+    /// Maximum wall time for the whole run, in seconds.
+    pub run_timeout: Option<u64>,
+    /// Run only the dependencies of the given tasks.
+    pub only_deps: bool,
+    /// Tasks to leave out of the run.
+    pub skip: Vec<String>,
+    /// How many tasks may run at the same time.
+    pub max_concurrency: Option<usize>,
+    /// Print a JSON report of the run instead of the progress UI and task outputs.
+    pub json: bool,
+    /// Run tasks even if their inputs haven't changed.
+    pub force: bool,
+    /// How many of the last lines of a failed task's output to show.
+    pub max_log_lines: Option<usize>,
+    /// Print how long each task took to stderr once the run finishes.
+    pub timings: bool,
+    /// Print the tasks' output as it is written.
+    pub stream: bool,
+    /// Don't prefix streamed output with the task name.
+    pub no_prefix: bool,
+    /// Print what would run instead of running anything.
+    pub dry_run: bool,
+}
+
+/// The outcome of [`Devenv::run_tasks`].
+#[derive(Debug, Clone, Serialize)]
+pub struct TaskRunReport {
+    /// Every task in the run, in scheduling order.
+    pub tasks: Vec<tasks::TaskReport>,
+    /// The outputs the tasks exported, by task name.
+    pub outputs: tasks::Outputs,
+}
+
+impl TaskRunReport {
+    /// Whether every task succeeded, or had nothing to do.
+    pub fn succeeded(&self) -> bool {
+        self.tasks.iter().all(|task| {
+            matches!(
+                task.status,
+                tasks::TaskReportStatus::Success
+                    | tasks::TaskReportStatus::Skipped
+                    | tasks::TaskReportStatus::Cached
+            )
+        })
+    }
+}
+
+/// Options for [`Devenv::new`].
+///
+/// Prefer [`DevenvOptions::builder`] over a struct literal,
+/// so that adding fields doesn't break your code.
 #[derive(Default)]
 pub struct DevenvOptions {
     pub config: config::Config,
@@ -31,6 +164,59 @@ pub struct DevenvOptions {
     pub logger: Option<log::Logger>,
     pub devenv_root: Option<PathBuf>,
     pub devenv_dotfile: Option<PathBuf>,
+    /// Receives logs, progress and task statuses instead of them being written to stderr.
+    pub event_sink: Option<log::EventSink>,
+}
+
+impl DevenvOptions {
+    pub fn builder() -> DevenvOptionsBuilder {
+        DevenvOptionsBuilder::default()
+    }
+}
+
+/// Builds [`DevenvOptions`]. Anything left unset falls back to the same defaults as [`Devenv::new`].
+#[derive(Default)]
+pub struct DevenvOptionsBuilder {
+    options: DevenvOptions,
+}
+
+impl DevenvOptionsBuilder {
+    pub fn config(mut self, config: config::Config) -> Self {
+        self.options.config = config;
+        self
+    }
+
+    pub fn global_options(mut self, global_options: cli::GlobalOptions) -> Self {
+        self.options.global_options = Some(global_options);
+        self
+    }
+
+    pub fn logger(mut self, logger: log::Logger) -> Self {
+        self.options.logger = Some(logger);
+        self
+    }
+
+    pub fn devenv_root<P: Into<PathBuf>>(mut self, devenv_root: P) -> Self {
+        self.options.devenv_root = Some(devenv_root.into());
+        self
+    }
+
+    pub fn devenv_dotfile<P: Into<PathBuf>>(mut self, devenv_dotfile: P) -> Self {
+        self.options.devenv_dotfile = Some(devenv_dotfile.into());
+        self
+    }
+
+    pub fn on_event<F>(mut self, on_event: F) -> Self
+    where
+        F: Fn(log::Event) + Send + Sync + 'static,
+    {
+        self.options.event_sink = Some(std::sync::Arc::new(on_event));
+        self
+    }
+
+    pub fn build(self) -> DevenvOptions {
+        self.options
+    }
 }
 
 pub struct Devenv {
@@ -39,6 +225,7 @@ pub struct Devenv {
 
     logger: log::Logger,
     log_progress: log::LogProgressCreator,
+    event_sink: Option<log::EventSink>,
 
     nix: cnix::Nix<'static>,
 
@@ -59,7 +246,8 @@ pub struct Devenv {
 }
 
 impl Devenv {
-    pub async fn new(options: DevenvOptions) -> Self {
+    /// Fails if Nix can't be found or the evaluation cache can't be opened.
+    pub async fn new(options: DevenvOptions) -> Result<Self> {
         let xdg_dirs = xdg::BaseDirectories::with_prefix("devenv").unwrap();
         let devenv_home = xdg_dirs.get_data_home();
         let cachix_trusted_keys = devenv_home.join("cachix_trusted_keys.json");
@@ -87,17 +275,29 @@ impl Devenv {
         let devenv_runtime =
             Path::new(&devenv_tmp).join(format!("devenv-{}", &devenv_state_hash[..7]));
 
-        let global_options = options.global_options.unwrap_or_default();
+        let mut global_options = options.global_options.unwrap_or_default();
+        global_options
+            .resolve_system(options.config.system.as_deref())
+            .map_err(|e| miette::miette!(e))?;
 
         let level = if global_options.verbose {
             log::Level::Debug
         } else {
             log::Level::Info
         };
-        let logger = options.logger.unwrap_or_else(|| log::Logger::new(level));
+        let mut logger = options
+            .logger
+            .unwrap_or_else(|| log::Logger::with_format(level, global_options.log_format));
+        if let Some(sink) = &options.event_sink {
+            logger = logger.with_sink(sink.clone());
+        }
 
-        let log_progress = if global_options.quiet {
+        let log_progress = if let Some(sink) = &options.event_sink {
+            log::LogProgressCreator::Sink(sink.clone())
+        } else if global_options.quiet {
             log::LogProgressCreator::Silent
+        } else if global_options.log_format == log::LogFormat::Json {
+            log::LogProgressCreator::Tracing
         } else {
             log::LogProgressCreator::Logging
         };
@@ -119,14 +319,14 @@ impl Devenv {
             devenv_dot_gc.clone(),
             devenv_root.clone(),
         )
-        .await
-        .unwrap(); // TODO: handle error
+        .await?;
 
-        Self {
+        Ok(Self {
             config: options.config,
             global_options,
             logger,
             log_progress,
+            event_sink: options.event_sink,
             devenv_root,
             devenv_dotfile,
             devenv_dot_gc,
@@ -137,7 +337,7 @@ impl Devenv {
             assembled: false,
             has_processes: None,
             container_name: None,
-        }
+        })
     }
 
     pub fn processes_log(&self) -> PathBuf {
@@ -194,7 +394,7 @@ impl Devenv {
         };
 
         // run direnv allow
-        std::process::Command::new(direnv)
+        let _ = std::process::Command::new(direnv)
             .arg("allow")
             .current_dir(&target)
             .exec();
@@ -202,8 +402,116 @@ impl Devenv {
     }
 
     pub fn inputs_add(&mut self, name: &str, url: &str, follows: &[String]) -> Result<()> {
+        // Don't write the overrides from devenv.local.yaml into devenv.yaml
+        let mut shared = config::Config::load_shared_from(&self.devenv_root)?;
+        shared.add_input(name, url, follows);
+        shared.write();
         self.config.add_input(name, url, follows);
-        self.config.write();
+        Ok(())
+    }
+
+    pub async fn why_depends(&mut self, package: &str, json: bool) -> Result<()> {
+        let env = self.get_dev_environment(false, true).await?;
+        let shell = fs::canonicalize(&env.gc_root).expect("Failed to resolve the shell gc root");
+
+        let closure = self.nix.closure(&shell).await?;
+        let matches = closure
+            .into_iter()
+            .filter(|path| store_path_matches(path, package))
+            .collect::<Vec<_>>();
+        let target = match matches.as_slice() {
+            [] => bail!("Package {package} is not part of the developer environment"),
+            [target] => target,
+            _ => match default_output(&matches) {
+                Some(target) => target,
+                None => bail!(
+                    "Package {package} matches several store paths, pass a more specific name:\n{}",
+                    matches
+                        .iter()
+                        .map(|p| p.display().to_string())
+                        .collect::<Vec<_>>()
+                        .join("\n")
+                ),
+            },
+        };
+
+        let chain = self.nix.why_depends(&shell, target).await?;
+        if json {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&chain).expect("Failed to serialize dependency chain")
+            );
+        } else {
+            for path in chain {
+                println!("{}", path.display());
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn cache_export(&mut self, file: &Path) -> Result<()> {
+        if file.exists() {
+            fs::remove_file(file).map_err(|e| {
+                miette::miette!("Failed to remove the existing {}: {}", file.display(), e)
+            })?;
+        }
+        self.nix.export_eval_cache(file).await?;
+        self.logger
+            .info(&format!("Exported evaluation cache to {}", file.display()));
+        Ok(())
+    }
+
+    pub async fn cache_import(&mut self, file: &Path) -> Result<()> {
+        let imported = self.nix.import_eval_cache(file).await?;
+        self.logger.info(&format!(
+            "Imported {imported} cached evaluations from {}",
+            file.display()
+        ));
+        Ok(())
+    }
+
+    pub async fn inputs_list(&mut self, json: bool) -> Result<()> {
+        self.assemble(false)?;
+
+        let lock = fs::read_to_string(self.devenv_root.join("devenv.lock")).ok();
+        let mut inputs = config::input_statuses(&self.config, lock.as_deref())?;
+        let paths = self.nix.input_paths().await?;
+        for input in inputs.iter_mut() {
+            if let Some(path) = paths.get(&input.name) {
+                input.fetched = path.exists();
+                input.store_path = Some(path.clone());
+            }
+        }
+
+        if json {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&inputs).expect("Failed to serialize inputs")
+            );
+            return Ok(());
+        }
+
+        let rows = inputs
+            .into_iter()
+            .map(|input| DevenvInputResult {
+                name: input.name,
+                type_: input.type_.unwrap_or_default(),
+                rev: input.rev.unwrap_or_default(),
+                follows: input
+                    .follows
+                    .into_iter()
+                    .chain(
+                        input
+                            .inputs_follow
+                            .into_iter()
+                            .map(|(sub, follows)| format!("{sub} -> {follows}")),
+                    )
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                fetched: if input.fetched { "yes" } else { "no" }.to_string(),
+            })
+            .collect::<Vec<_>>();
+        print_stderr(rows.with_title()).expect("Failed to print inputs");
         Ok(())
     }
 
@@ -216,13 +524,39 @@ impl Devenv {
         Ok(())
     }
 
+    pub async fn env(&mut self, format: cli::EnvFormat) -> Result<()> {
+        match format {
+            cli::EnvFormat::Shell => self.print_dev_env(false).await,
+            cli::EnvFormat::Systemd => {
+                let env = self.get_dev_environment(true, false).await?;
+                let dev_env: DevEnvJson =
+                    serde_json::from_slice(&env.output).expect("Failed to parse dev env");
+                let vars = dev_env
+                    .variables
+                    .into_iter()
+                    .filter_map(|(name, var)| match var {
+                        DevEnvVariable {
+                            type_,
+                            value: serde_json::Value::String(value),
+                        } if type_ == "exported" => Some((name, value)),
+                        _ => None,
+                    })
+                    .collect::<BTreeMap<_, _>>();
+                print!("{}", systemd_env_file(&vars)?);
+                Ok(())
+            }
+        }
+    }
+
+    #[tracing::instrument(skip(self))]
     pub async fn shell(
         &mut self,
         cmd: &Option<String>,
         args: &[String],
+        keep: &[String],
         replace_shell: bool,
     ) -> Result<()> {
-        let develop_args = self.prepare_develop_args(cmd, args).await?;
+        let develop_args = self.prepare_develop_args_keeping(cmd, args, keep).await?;
 
         let develop_args = develop_args
             .iter()
@@ -237,6 +571,16 @@ impl Devenv {
         &mut self,
         cmd: &Option<String>,
         args: &[String],
+    ) -> Result<Vec<String>> {
+        self.prepare_develop_args_keeping(cmd, args, &[]).await
+    }
+
+    /// Like [`Devenv::prepare_develop_args`], also letting `keep` through in a clean shell.
+    async fn prepare_develop_args_keeping(
+        &mut self,
+        cmd: &Option<String>,
+        args: &[String],
+        keep: &[String],
     ) -> Result<Vec<String>> {
         self.assemble(false)?;
         let env = self.get_dev_environment(false, true).await?;
@@ -246,23 +590,24 @@ impl Devenv {
             env.gc_root.to_str().expect("gc root should be utf-8"),
         ];
 
-        let default_clean = config::Clean {
-            enabled: false,
-            keep: vec![],
-        };
-        let config_clean = self.config.clean.as_ref().unwrap_or(&default_clean);
-        if self.global_options.clean.is_some() || config_clean.enabled {
+        let clean_keep = clean_keep(
+            self.global_options.clean.as_deref(),
+            self.config.clean.as_ref(),
+            keep,
+        );
+        if clean_keep.is_none() && !keep.is_empty() {
+            self.logger
+                .warn("--keep only has an effect in a clean shell, see --clean.");
+        }
+        if let Some(clean_keep) = &clean_keep {
             develop_args.push("--ignore-environment");
 
-            let keep = match &self.global_options.clean {
-                Some(clean) => clean,
-                None => &config_clean.keep,
-            };
-
-            for env in keep {
+            for env in clean_keep {
                 develop_args.push("--keep");
                 develop_args.push(env);
             }
+            develop_args.push("--keep");
+            develop_args.push(NO_BANNER_ENV);
 
             develop_args.push("-c");
             develop_args.push("bash");
@@ -278,7 +623,9 @@ impl Devenv {
                 develop_args.extend_from_slice(&args);
             }
             None => {
-                self.logger.info("Entering shell");
+                if std::env::var_os(NO_BANNER_ENV).is_none() {
+                    self.logger.info("Entering shell");
+                }
             }
         };
 
@@ -440,7 +787,14 @@ impl Devenv {
         Ok(())
     }
 
-    pub async fn search(&mut self, name: &str) -> Result<()> {
+    /// Remove the selected categories of state from `.devenv`, leaving everything else.
+    ///
+    /// The evaluation cache is open while `Devenv` exists, so prefer [`clean`] to remove it.
+    pub fn clean(&self, categories: &[StateCategory]) -> Result<()> {
+        clean(&self.logger, &self.devenv_dotfile, categories)
+    }
+
+    pub async fn search(&mut self, name: &str, json: bool) -> Result<()> {
         self.assemble(false)?;
 
         let options = self.nix.build(&["optionsJSON"]).await?;
@@ -483,18 +837,69 @@ impl Devenv {
             .collect::<Vec<_>>();
         let search_results_count = search_results.len();
 
-        if !search_results.is_empty() {
-            print_stderr(search_results.with_title()).expect("Failed to print search results");
+        let packages_by_namespace = group_by_namespace(search_results, |r| &r.name);
+        let options_by_namespace = group_by_namespace(options_results, |r| &r.name);
+
+        if json {
+            let mut grouped: BTreeMap<String, Vec<SearchMatch>> = BTreeMap::new();
+            for (namespace, results) in packages_by_namespace {
+                grouped
+                    .entry(namespace)
+                    .or_default()
+                    .extend(results.into_iter().map(SearchMatch::Package));
+            }
+            for (namespace, results) in options_by_namespace {
+                grouped
+                    .entry(namespace)
+                    .or_default()
+                    .extend(results.into_iter().map(SearchMatch::Option));
+            }
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&grouped).expect("Failed to serialize search results")
+            );
+            return Ok(());
         }
 
-        if !options_results.is_empty() {
-            print_stderr(options_results.with_title()).expect("Failed to print options results");
+        for (namespace, results) in packages_by_namespace {
+            eprintln!("{}", console::style(namespace).bold());
+            print_stderr(results.with_title()).expect("Failed to print search results");
+        }
+
+        for (namespace, results) in options_by_namespace {
+            eprintln!("{}", console::style(namespace).bold());
+            print_stderr(results.with_title()).expect("Failed to print options results");
         }
 
         self.logger.info(&format!("Found {search_results_count} packages and {results_options_count} options for '{name}'."));
         Ok(())
     }
 
+    /// Evaluate an attribute of the environment's config and deserialize it into `T`.
+    ///
+    /// The attribute path is relative to the config, e.g. `languages.python.enable`.
+    /// Failures are reported as an [`EvalError`] naming the attribute.
+    ///
+    /// ```no_run
+    /// # async fn example(devenv: &mut devenv::Devenv) -> miette::Result<()> {
+    /// let python: bool = devenv.eval("languages.python.enable").await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn eval<T: DeserializeOwned>(&mut self, attribute: &str) -> Result<T> {
+        self.assemble(false)?;
+        let attribute = attribute.strip_prefix("config.").unwrap_or(attribute);
+        let json = self
+            .nix
+            .eval(&[&format!("devenv.{attribute}")])
+            .await
+            .map_err(|e| EvalError::Nix {
+                attribute: attribute.to_string(),
+                message: e.to_string(),
+            })?;
+        Ok(deserialize_eval(attribute, &json)?)
+    }
+
     pub async fn has_processes(&mut self) -> Result<bool> {
         if self.has_processes.is_none() {
             let processes = self.nix.eval(&["devenv.processes"]).await?;
@@ -503,7 +908,88 @@ impl Devenv {
         Ok(self.has_processes.unwrap())
     }
 
-    pub async fn tasks_run(&mut self, roots: Vec<String>) -> Result<()> {
+    /// Run tasks like `devenv tasks run` does, but without drawing the progress UI,
+    /// returning the outcome of every task instead of failing on the first unsuccessful one.
+    ///
+    /// Task statuses and, with `stream`, lines of output are still sent to the event sink,
+    /// if one was configured. The options that only shape the command's output,
+    /// `json`, `timings` and `dry_run`, are ignored.
+    pub async fn run_tasks(
+        &mut self,
+        roots: Vec<String>,
+        options: TasksRunOptions,
+    ) -> Result<TaskRunReport> {
+        let tui = self.tasks_ui(roots, &options).await?;
+        task_run_report(tui, self.event_sink.clone()).await
+    }
+
+    #[tracing::instrument(skip(self, options))]
+    pub async fn tasks_run(&mut self, roots: Vec<String>, options: TasksRunOptions) -> Result<()> {
+        let mut tui = self.tasks_ui(roots, &options).await?;
+        if options.dry_run {
+            let plan = tui.plan().await;
+            if options.json {
+                println!(
+                    "{}",
+                    serde_json::to_string(&plan).expect("Failed to serialize the plan")
+                );
+            } else {
+                print!("{}", tasks::format_plan(&plan));
+            }
+            return Ok(());
+        }
+        let (tasks_status, outputs) = if options.json {
+            tui.run_json(std::io::stdout()).await?
+        } else if let Some(sink) = &self.event_sink {
+            tui.run_with_output(
+                |report| {
+                    sink(log::Event::Task(report));
+                    Ok(())
+                },
+                |line| {
+                    sink(log::Event::Log {
+                        level: log::Level::Info,
+                        message: line.to_string(),
+                    })
+                },
+            )
+            .await?
+        } else {
+            tui.run().await?
+        };
+
+        if options.timings {
+            eprint!("{}", tasks::timings(&tui.report().await));
+        }
+
+        if tasks_status.aborted > 0 {
+            miette::bail!(
+                "Run exceeded its timeout, {} tasks were aborted",
+                tasks_status.aborted
+            );
+        }
+        if tasks_status.failed > 0
+            || tasks_status.dependency_failed > 0
+            || tasks_status.timed_out > 0
+        {
+            miette::bail!("Some tasks failed");
+        }
+
+        if !options.json {
+            println!(
+                "{}",
+                serde_json::to_string(&outputs).expect("parsing of outputs failed")
+            );
+        }
+        Ok(())
+    }
+
+    /// Evaluate the tasks and set up a run of `roots`.
+    async fn tasks_ui(
+        &mut self,
+        roots: Vec<String>,
+        options: &TasksRunOptions,
+    ) -> Result<tasks::TasksUi> {
         self.assemble(false)?;
         if roots.is_empty() {
             bail!("No tasks specified.");
@@ -518,23 +1004,26 @@ impl Devenv {
         let tasks: Vec<tasks::TaskConfig> =
             serde_json::from_str(&tasks_json).expect("Failed to parse tasks config");
         // run tasks
-        let config = tasks::Config { roots, tasks };
+        let config = tasks::Config {
+            roots,
+            tasks,
+            env: options.env.iter().cloned().collect(),
+            run_timeout: options.run_timeout,
+            only_deps: options.only_deps,
+            skip: options.skip.clone(),
+            max_concurrency: options.max_concurrency,
+            state_dir: Some(self.devenv_dotfile.join("state").join("tasks")),
+            force: options.force,
+            max_output_lines: options.max_log_lines,
+            log_dir: Some(self.devenv_dotfile.join("state").join("tasks").join("logs")),
+            stream_output: options.stream,
+            no_prefix: options.no_prefix,
+        };
         self.logger.debug(&format!(
             "Tasks config: {}",
             serde_json::to_string_pretty(&config).unwrap()
         ));
-        let mut tui = tasks::TasksUi::new(config).await?;
-        let (tasks_status, outputs) = tui.run().await?;
-
-        if tasks_status.failed > 0 || tasks_status.dependency_failed > 0 {
-            miette::bail!("Some tasks failed");
-        }
-
-        println!(
-            "{}",
-            serde_json::to_string(&outputs).expect("parsing of outputs failed")
-        );
-        Ok(())
+        Ok(tasks::TasksUi::new(config).await?)
     }
 
     pub async fn test(&mut self) -> Result<()> {
@@ -588,6 +1077,92 @@ impl Devenv {
         Ok(())
     }
 
+    pub async fn check_updates(&mut self, json: bool) -> Result<()> {
+        if self.global_options.offline {
+            bail!("Checking inputs for updates needs network access, run without --offline.");
+        }
+        self.assemble(false)?;
+
+        let lock = fs::read_to_string(self.devenv_root.join("devenv.lock")).ok();
+        let inputs = config::input_statuses(&self.config, lock.as_deref())?;
+        let mut latest = HashMap::new();
+        {
+            let _logprogress = self
+                .log_progress
+                .without_newline("Checking inputs for updates");
+            for url in inputs.iter().filter_map(|input| input.upstream_url()) {
+                if let Some(rev) = self.nix.latest_rev(url).await? {
+                    latest.insert(url.to_string(), rev);
+                }
+            }
+        }
+        let updates = config::input_updates(&inputs, &latest);
+
+        if json {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&updates).expect("Failed to serialize updates")
+            );
+            return Ok(());
+        }
+
+        for update in &updates {
+            match &update.latest_rev {
+                Some(latest) if update.outdated => println!(
+                    "{}: {} -> {}",
+                    update.name,
+                    short_rev(&update.locked_rev),
+                    short_rev(latest)
+                ),
+                Some(_) => {}
+                None => self
+                    .logger
+                    .warn(&format!("{}: couldn't query {}", update.name, update.url)),
+            }
+        }
+        if !updates.iter().any(|update| update.outdated) {
+            self.logger.info("All inputs are up to date.");
+        }
+        Ok(())
+    }
+
+    /// Build an attribute of the environment's config and return its store path.
+    ///
+    /// The attribute path is relative to the config, e.g. `outputs.myapp`.
+    /// Failures are reported as a [`BuildError`] naming the attribute,
+    /// carrying the [`NixError`](crate::NixError) when Nix's output is recognized.
+    ///
+    /// ```no_run
+    /// # async fn example(devenv: &mut devenv::Devenv) -> miette::Result<()> {
+    /// let path = devenv.build_attribute("languages.python.package").await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn build_attribute(&mut self, attribute: &str) -> Result<PathBuf> {
+        self.assemble(false)?;
+        let attribute = attribute.strip_prefix("config.").unwrap_or(attribute);
+        let paths = self
+            .nix
+            .build(&[&format!("devenv.{attribute}")])
+            .await
+            .map_err(|e| match e.downcast::<crate::NixError>() {
+                Ok(source) => BuildError::Nix {
+                    attribute: attribute.to_string(),
+                    source,
+                },
+                Err(e) => BuildError::Command {
+                    attribute: attribute.to_string(),
+                    message: e.to_string(),
+                },
+            })?;
+        paths.into_iter().next().ok_or_else(|| {
+            BuildError::NoOutput {
+                attribute: attribute.to_string(),
+            }
+            .into()
+        })
+    }
+
     pub async fn build(&mut self, attributes: &[String]) -> Result<()> {
         self.assemble(false)?;
         let attributes: Vec<String> = if attributes.is_empty() {
@@ -627,6 +1202,7 @@ impl Devenv {
         Ok(())
     }
 
+    #[tracing::instrument(skip(self))]
     pub async fn up(
         &mut self,
         process: Option<&str>,
@@ -640,6 +1216,20 @@ impl Devenv {
             bail!("No processes defined");
         }
 
+        let processes = self.nix.eval(&["devenv.processes"]).await?;
+        let processes: serde_json::Value = serde_json::from_str(&processes)
+            .map_err(|e| miette::miette!("Failed to parse processes: {}", e))?;
+        let conflicts = port_conflicts(&processes);
+        if !conflicts.is_empty() {
+            for (port, names) in &conflicts {
+                self.logger.error(&format!(
+                    "Port {port} is declared by several processes: {}",
+                    names.join(", ")
+                ));
+            }
+            bail!("Processes have conflicting ports");
+        }
+
         let proc_script_string: String;
         {
             let _logprogress = self.log_progress.with_newline("Building processes");
@@ -724,6 +1314,89 @@ impl Devenv {
         }
     }
 
+    pub async fn processes_status(&mut self, json: bool) -> Result<()> {
+        let mut status = processes_status(&self.processes_pid());
+        if status.state == ProcessesState::Running {
+            match self.process_compose_list().await {
+                Ok(processes) => status.processes = processes,
+                Err(e) => self
+                    .logger
+                    .warn(&format!("Failed to get the state of each process: {e}")),
+            }
+        }
+        if json {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&status).expect("Failed to serialize status")
+            );
+            return Ok(());
+        }
+        match (&status.state, status.pid) {
+            (ProcessesState::Running, Some(pid)) => self.logger.info(&format!(
+                "Processes are running with PID {pid} for {}s.",
+                status.uptime_secs.unwrap_or_default()
+            )),
+            (ProcessesState::Stopped, Some(pid)) => self.logger.warn(&format!(
+                "Processes with PID {pid} exited without running `devenv processes down`."
+            )),
+            _ => self.logger.info("No processes running."),
+        }
+        if !status.processes.is_empty() {
+            let width = status
+                .processes
+                .iter()
+                .map(|process| process.name.len())
+                .max()
+                .unwrap_or(0);
+            for process in &status.processes {
+                println!(
+                    "{:width$}  {:10}  {:>8}  {:>6}s  {} restarts",
+                    process.name,
+                    process.state,
+                    process.pid.map(|pid| pid.to_string()).unwrap_or_default(),
+                    process.uptime_secs.unwrap_or_default(),
+                    process.restarts,
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Ask process-compose for the state of every process it manages.
+    ///
+    /// Other process managers don't expose this, so nothing is returned for them.
+    async fn process_compose_list(&mut self) -> Result<Vec<ProcessStatus>> {
+        self.assemble(false)?;
+        let implementation: String = self.eval("process.manager.implementation").await?;
+        if implementation != "process-compose" {
+            return Ok(Vec::new());
+        }
+        let args: serde_json::Value = self.eval("process.manager.args").await?;
+        let package = self
+            .build_attribute("process.managers.process-compose.package")
+            .await?;
+
+        let mut cmd = std::process::Command::new(package.join("bin").join("process-compose"));
+        cmd.args(["process", "list", "--output", "json"]);
+        // Reach the server the same way `devenv up` started it
+        match (args["unix-socket"].as_str(), args["port"].as_str()) {
+            (Some(socket), _) => cmd.args(["--unix-socket", socket]),
+            (None, Some(port)) => cmd.args(["--port", port]),
+            (None, None) => &mut cmd,
+        };
+        let output = cmd
+            .output()
+            .map_err(|e| miette::miette!("Failed to run process-compose: {}", e))?;
+        if !output.status.success() {
+            bail!(
+                "process-compose failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        parse_process_list(&output.stdout)
+            .map_err(|e| miette::miette!("Failed to parse the output of process-compose: {}", e))
+    }
+
     pub fn down(&self) -> Result<()> {
         if !PathBuf::from(&self.processes_pid()).exists() {
             self.logger.error("No processes running.");
@@ -780,24 +1453,33 @@ impl Devenv {
                 }
             }
         }
-        fs::write(
+        write_file_if_changed(
             self.devenv_dotfile.join("flake.json"),
             serde_json::to_string(&flake_inputs).unwrap(),
         )
         .expect("Failed to write flake.json");
-        fs::write(
+        write_file_if_changed(
             self.devenv_dotfile.join("devenv.json"),
             serde_json::to_string(&self.config).unwrap(),
         )
         .expect("Failed to write devenv.json");
         // TODO: superceded by eval caching.
         // Remove once direnvrc migration is implemented.
-        fs::write(
+        write_file_if_changed(
             self.devenv_dotfile.join("imports.txt"),
             self.config.imports.join("\n"),
         )
         .expect("Failed to write imports.txt");
 
+        for (name, path) in &self.global_options.pin_package {
+            if !path.exists() {
+                bail!(
+                    "Pinned path for package {name} does not exist in the store: {}",
+                    path.display()
+                );
+            }
+        }
+
         // create flake.devenv.nix
         let vars = indoc::formatdoc!(
             "version = \"{}\";
@@ -809,9 +1491,10 @@ impl Devenv {
             devenv_tmpdir = \"{}\";
             devenv_runtime = \"{}\";
             devenv_istesting = {};
+            pinned_packages = {};
             ",
             crate_version!(),
-            self.global_options.system,
+            self.global_options.system(),
             self.devenv_root.display(),
             self.devenv_dotfile.file_name().unwrap().to_str().unwrap(),
             self.devenv_dotfile.file_name().unwrap().to_str().unwrap(),
@@ -821,10 +1504,16 @@ impl Devenv {
                 .unwrap_or_else(|| "null".to_string()),
             self.devenv_tmp,
             self.devenv_runtime.display(),
-            is_testing
+            is_testing,
+            pinned_packages_nix(&self.global_options.pin_package),
+        );
+        let flake = FLAKE_TMPL.replace("__DEVENV_VARS__", &vars).replace(
+            "__DEVENV_NIX_CONFIG__",
+            &nix_config_nix(&self.config.nix_config),
         );
-        let flake = FLAKE_TMPL.replace("__DEVENV_VARS__", &vars);
-        std::fs::write(self.devenv_root.join(DEVENV_FLAKE), flake)
+        // Keep the previous file (and its mtime) when nothing changed,
+        // so Nix doesn't see a new flake on every invocation.
+        write_file_if_changed(self.devenv_root.join(DEVENV_FLAKE), flake)
             .expect("Failed to write flake.nix");
 
         self.assembled = true;
@@ -863,6 +1552,19 @@ pub struct DevEnv {
     gc_root: PathBuf,
 }
 
+/// The subset of `nix print-dev-env --json` output we care about.
+#[derive(Deserialize)]
+struct DevEnvJson {
+    variables: BTreeMap<String, DevEnvVariable>,
+}
+
+#[derive(Deserialize)]
+struct DevEnvVariable {
+    #[serde(rename = "type")]
+    type_: String,
+    value: serde_json::Value,
+}
+
 #[derive(Deserialize)]
 struct PackageResults(HashMap<String, PackageResult>);
 
@@ -883,11 +1585,12 @@ struct OptionResult {
     description: String,
 }
 
-#[derive(Table)]
+#[derive(Table, Serialize)]
 struct DevenvOptionResult {
     #[table(title = "Option")]
     name: String,
     #[table(title = "Type")]
+    #[serde(rename = "type")]
     type_: String,
     #[table(title = "Default")]
     default: String,
@@ -895,7 +1598,7 @@ struct DevenvOptionResult {
     description: String,
 }
 
-#[derive(Table)]
+#[derive(Table, Serialize)]
 struct DevenvPackageResult {
     #[table(title = "Package")]
     name: String,
@@ -905,6 +1608,298 @@ struct DevenvPackageResult {
     description: String,
 }
 
+#[derive(Table)]
+struct DevenvInputResult {
+    #[table(title = "Input")]
+    name: String,
+    #[table(title = "Type")]
+    type_: String,
+    #[table(title = "Revision")]
+    rev: String,
+    #[table(title = "Follows")]
+    follows: String,
+    #[table(title = "Fetched")]
+    fetched: String,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum SearchMatch {
+    Package(DevenvPackageResult),
+    Option(DevenvOptionResult),
+}
+
+/// Group results by the first segment of their attribute path,
+/// e.g. `languages.python.enable` goes under `languages`.
+fn group_by_namespace<T>(results: Vec<T>, name: impl Fn(&T) -> &str) -> BTreeMap<String, Vec<T>> {
+    let mut grouped: BTreeMap<String, Vec<T>> = BTreeMap::new();
+    for result in results {
+        let namespace = name(&result)
+            .split('.')
+            .next()
+            .unwrap_or_default()
+            .to_string();
+        grouped.entry(namespace).or_default().push(result);
+    }
+    for results in grouped.values_mut() {
+        results.sort_by(|a, b| name(a).cmp(name(b)));
+    }
+    grouped
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum ProcessesState {
+    /// The process manager is alive.
+    Running,
+    /// There is a pid file, but the process manager is gone.
+    Stopped,
+    /// `devenv up --detach` isn't active.
+    NotStarted,
+}
+
+#[derive(Serialize, Debug)]
+struct ProcessesStatus {
+    state: ProcessesState,
+    pid: Option<i32>,
+    uptime_secs: Option<u64>,
+    /// Every process, if the process manager can report them.
+    processes: Vec<ProcessStatus>,
+}
+
+/// A single process, as reported by process-compose.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+struct ProcessStatus {
+    name: String,
+    /// As named by process-compose, e.g. `Running`, `Completed` or `Restarting`.
+    state: String,
+    pid: Option<i64>,
+    uptime_secs: Option<u64>,
+    restarts: u64,
+}
+
+/// Parse the output of `process-compose process list --output json`.
+fn parse_process_list(json: &[u8]) -> serde_json::Result<Vec<ProcessStatus>> {
+    #[derive(Deserialize)]
+    struct ProcessState {
+        name: String,
+        status: String,
+        #[serde(default)]
+        pid: i64,
+        #[serde(default)]
+        restarts: u64,
+        /// Nanoseconds since the process started.
+        #[serde(default)]
+        age: u64,
+    }
+
+    let states: Vec<ProcessState> = serde_json::from_slice(json)?;
+    Ok(states
+        .into_iter()
+        .map(|state| {
+            // A pid of 0 means the process isn't running
+            let running = state.pid > 0;
+            ProcessStatus {
+                name: state.name,
+                state: state.status,
+                pid: running.then_some(state.pid),
+                uptime_secs: running.then_some(state.age / 1_000_000_000),
+                restarts: state.restarts,
+            }
+        })
+        .collect())
+}
+
+/// Inspect the process manager started by `devenv up --detach` through its pid file.
+fn processes_status(pid_file: &Path) -> ProcessesStatus {
+    let pid = fs::read_to_string(pid_file)
+        .ok()
+        .and_then(|pid| pid.trim().parse::<i32>().ok());
+    let Some(pid) = pid else {
+        return ProcessesStatus {
+            state: ProcessesState::NotStarted,
+            pid: None,
+            uptime_secs: None,
+            processes: Vec::new(),
+        };
+    };
+
+    // Signal 0 only checks whether the process exists
+    if signal::kill(Pid::from_raw(pid), None).is_err() {
+        return ProcessesStatus {
+            state: ProcessesState::Stopped,
+            pid: Some(pid),
+            uptime_secs: None,
+            processes: Vec::new(),
+        };
+    }
+
+    // The pid file is written right after the process manager is spawned
+    let uptime_secs = fs::metadata(pid_file)
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|started| started.elapsed().ok())
+        .map(|uptime| uptime.as_secs());
+    ProcessesStatus {
+        state: ProcessesState::Running,
+        pid: Some(pid),
+        uptime_secs,
+        processes: Vec::new(),
+    }
+}
+
+/// Find ports declared by more than one process, through their `ports` option
+/// or the http probes of their process-compose settings.
+fn port_conflicts(processes: &serde_json::Value) -> BTreeMap<u64, Vec<String>> {
+    let mut by_port: BTreeMap<u64, Vec<String>> = BTreeMap::new();
+    let Some(processes) = processes.as_object() else {
+        return by_port;
+    };
+    for (name, process) in processes {
+        let declared = process["ports"].as_array().into_iter().flatten();
+        let probes = ["readiness_probe", "liveness_probe"]
+            .into_iter()
+            .map(|probe| &process["process-compose"][probe]["http_get"]["port"]);
+        let mut ports = declared
+            .chain(probes)
+            .filter_map(|p| p.as_u64())
+            .collect::<Vec<_>>();
+        // A probe usually checks a port the process also declares
+        ports.sort();
+        ports.dedup();
+        for port in ports {
+            by_port.entry(port).or_default().push(name.clone());
+        }
+    }
+    by_port.retain(|_, names| names.len() > 1);
+    by_port
+}
+
+fn short_rev(rev: &str) -> &str {
+    rev.get(..7).unwrap_or(rev)
+}
+
+/// Check whether `path` is a store path named `name`, either exactly (`openssl-3.0.13`)
+/// or ignoring the version (`openssl`).
+fn store_path_matches(path: &Path, name: &str) -> bool {
+    let Some(file_name) = path.file_name().and_then(|n| n.to_str()) else {
+        return false;
+    };
+    let Some((_hash, full_name)) = file_name.split_once('-') else {
+        return false;
+    };
+    full_name == name
+        || full_name
+            .strip_prefix(name)
+            .and_then(|rest| rest.strip_prefix('-'))
+            .is_some_and(|version| version.starts_with(|c: char| c.is_ascii_digit()))
+}
+
+/// Among store paths of the same package, the one of its default output,
+/// e.g. `openssl-3.0.13` rather than `openssl-3.0.13-bin` or `openssl-3.0.13-dev`.
+///
+/// `None` if the paths belong to different packages or versions.
+fn default_output(paths: &[PathBuf]) -> Option<&PathBuf> {
+    let name = |path: &PathBuf| {
+        path.file_name()
+            .and_then(|n| n.to_str())
+            .and_then(|n| n.split_once('-'))
+            .map(|(_hash, name)| name.to_string())
+    };
+    paths.iter().find(|candidate| {
+        let Some(prefix) = name(candidate).map(|name| format!("{name}-")) else {
+            return false;
+        };
+        paths
+            .iter()
+            .filter(|path| path != candidate)
+            .all(|path| name(path).is_some_and(|name| name.starts_with(&prefix)))
+    })
+}
+
+/// Render variables in the format of systemd's `EnvironmentFile=`.
+///
+/// Values are written unquoted with backslashes and quotes escaped.
+/// `%` is left as is, since specifiers aren't expanded in the file's contents.
+/// systemd has no way to express multi-line values, so those are rejected.
+fn systemd_env_file(vars: &BTreeMap<String, String>) -> Result<String> {
+    let mut output = String::new();
+    for (name, value) in vars {
+        if value.contains('\n') || value.contains('\r') {
+            bail!("Variable {name} has a multi-line value, which systemd's EnvironmentFile= doesn't support");
+        }
+        let mut escaped = String::with_capacity(value.len());
+        for c in value.chars() {
+            match c {
+                '\\' | '"' | '\'' => {
+                    escaped.push('\\');
+                    escaped.push(c);
+                }
+                c => escaped.push(c),
+            }
+        }
+        output.push_str(&format!("{name}={escaped}\n"));
+    }
+    Ok(output)
+}
+
+/// Deserialize the JSON that evaluating `attribute` produced.
+fn deserialize_eval<T: DeserializeOwned>(attribute: &str, json: &str) -> Result<T, EvalError> {
+    serde_json::from_str(json).map_err(|source| EvalError::Deserialize {
+        attribute: attribute.to_string(),
+        source,
+    })
+}
+
+/// Render `--pin-package` overrides as a Nix attrset of package names to store paths.
+fn pinned_packages_nix(pins: &[(String, PathBuf)]) -> String {
+    let entries = pins
+        .iter()
+        .map(|(name, path)| {
+            let path = serde_json::to_string(&path.to_string_lossy())
+                .unwrap()
+                .replace("${", "\\${");
+            format!("\"{}\" = {}; ", name, path)
+        })
+        .collect::<String>();
+    format!("{{ {entries}}}")
+}
+
+/// The variables to let through when entering a clean shell, or `None` if the shell isn't clean.
+///
+/// `--clean` replaces the `keep` list from devenv.yaml, and `--keep` adds to either of them.
+fn clean_keep(
+    clean_flag: Option<&[String]>,
+    config_clean: Option<&config::Clean>,
+    keep: &[String],
+) -> Option<Vec<String>> {
+    let base = match (clean_flag, config_clean) {
+        (Some(clean), _) => clean,
+        (None, Some(clean)) if clean.enabled => clean.keep.as_slice(),
+        _ => return None,
+    };
+    let mut vars = base.to_vec();
+    for var in keep {
+        if !vars.contains(var) {
+            vars.push(var.clone());
+        }
+    }
+    Some(vars)
+}
+
+/// Render `nixConfig` settings from devenv.yaml as a Nix attribute set, sorted by name.
+fn nix_config_nix(settings: &HashMap<String, String>) -> String {
+    let settings: BTreeMap<_, _> = settings.iter().collect();
+    let entries = settings
+        .into_iter()
+        .map(|(name, value)| {
+            let value = serde_json::to_string(value).unwrap().replace("${", "\\${");
+            format!("\"{}\" = {}; ", name, value)
+        })
+        .collect::<String>();
+    format!("{{ {entries}}}")
+}
+
 fn cleanup_symlinks(root: &Path) -> (Vec<PathBuf>, Vec<PathBuf>) {
     let mut to_gc = Vec::new();
     let mut removed_symlinks = Vec::new();
@@ -928,3 +1923,496 @@ fn cleanup_symlinks(root: &Path) -> (Vec<PathBuf>, Vec<PathBuf>) {
 
     (to_gc, removed_symlinks)
 }
+
+/// Run `tui` to completion, sending task statuses and streamed output to `sink`.
+/// Without a sink, streamed output is written to stderr.
+async fn task_run_report(
+    mut tui: tasks::TasksUi,
+    sink: Option<log::EventSink>,
+) -> Result<TaskRunReport> {
+    let (_, outputs) = tui
+        .run_with_output(
+            |report| {
+                if let Some(sink) = &sink {
+                    sink(log::Event::Task(report));
+                }
+                Ok(())
+            },
+            |line| match &sink {
+                Some(sink) => sink(log::Event::Log {
+                    level: log::Level::Info,
+                    message: line.to_string(),
+                }),
+                None => eprintln!("{line}"),
+            },
+        )
+        .await?;
+    Ok(TaskRunReport {
+        tasks: tui.report().await,
+        outputs,
+    })
+}
+
+/// Remove the selected categories of state from `dotfile`, leaving everything else.
+///
+/// Unlike [`Devenv::clean`], this doesn't need a `Devenv`,
+/// so nothing holds the evaluation cache open while it is removed.
+pub fn clean(logger: &log::Logger, dotfile: &Path, categories: &[StateCategory]) -> Result<()> {
+    if categories.is_empty() {
+        bail!("Nothing to clean, pass --logs, --eval-cache, --task-state or --all.");
+    }
+    for (category, freed) in clean_state(dotfile, categories)? {
+        logger.info(&format!(
+            "Removed {}, freeing {}.",
+            category.name(),
+            format_size(freed)
+        ));
+    }
+    Ok(())
+}
+
+/// Remove the paths of each category under `dotfile`, returning the bytes freed per category.
+fn clean_state(dotfile: &Path, categories: &[StateCategory]) -> Result<Vec<(StateCategory, u64)>> {
+    let root = match fs::canonicalize(dotfile) {
+        Ok(root) => root,
+        // nothing has been created yet
+        Err(_) => return Ok(categories.iter().map(|c| (*c, 0)).collect()),
+    };
+    let mut freed = Vec::new();
+    for category in categories {
+        let mut bytes = 0;
+        for path in category.paths(&root) {
+            let Ok(metadata) = fs::symlink_metadata(&path) else {
+                continue;
+            };
+            // don't follow symlinks out of the state directory
+            let parent = path
+                .parent()
+                .and_then(|parent| fs::canonicalize(parent).ok());
+            if !parent.is_some_and(|parent| parent.starts_with(&root)) {
+                bail!(
+                    "Refusing to remove {} outside of {}",
+                    path.display(),
+                    root.display()
+                );
+            }
+            bytes += disk_usage(&path);
+            let removed = if metadata.is_dir() {
+                fs::remove_dir_all(&path)
+            } else {
+                fs::remove_file(&path)
+            };
+            removed.map_err(|e| miette::miette!("Failed to remove {}: {}", path.display(), e))?;
+        }
+        freed.push((*category, bytes));
+    }
+    Ok(freed)
+}
+
+/// Total size of the files at `path`, without following symlinks.
+fn disk_usage(path: &Path) -> u64 {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => fs::read_dir(path)
+            .into_iter()
+            .flatten()
+            .filter_map(|entry| entry.ok())
+            .map(|entry| disk_usage(&entry.path()))
+            .sum(),
+        Ok(metadata) => metadata.len(),
+        Err(_) => 0,
+    }
+}
+
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{size:.1} {}", UNITS[unit])
+}
+
+/// Write `contents` to `path` unless the file already holds exactly the same bytes.
+///
+/// Returns whether the file was (re)written.
+fn write_file_if_changed<P: AsRef<Path>, C: AsRef<[u8]>>(
+    path: P,
+    contents: C,
+) -> std::io::Result<bool> {
+    let path = path.as_ref();
+    let contents = contents.as_ref();
+    if let Ok(existing) = fs::read(path) {
+        if existing == contents {
+            return Ok(false);
+        }
+    }
+    fs::write(path, contents)?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_task_run_report() {
+        use std::sync::{Arc, Mutex};
+
+        let config = tasks::Config::try_from(serde_json::json!({
+            "roots": ["myapp:build", "myapp:test"],
+            "tasks": [
+                { "name": "myapp:build", "command": "true" },
+                { "name": "myapp:test", "command": "false", "after": ["myapp:build"] }
+            ]
+        }))
+        .unwrap();
+        let tui = tasks::TasksUi::new(config).await.unwrap();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let sink: log::EventSink = {
+            let events = Arc::clone(&events);
+            Arc::new(move |event: log::Event| {
+                if let log::Event::Task(report) = event {
+                    events.lock().unwrap().push(report.name);
+                }
+            })
+        };
+
+        let report = task_run_report(tui, Some(sink)).await.unwrap();
+        assert!(!report.succeeded());
+        let statuses: Vec<_> = report
+            .tasks
+            .iter()
+            .map(|task| (task.name.as_str(), task.status.clone()))
+            .collect();
+        assert_eq!(
+            statuses,
+            [
+                ("myapp:build", tasks::TaskReportStatus::Success),
+                ("myapp:test", tasks::TaskReportStatus::Failed)
+            ]
+        );
+        let events = events.lock().unwrap();
+        assert!(events.contains(&"myapp:build".to_string()));
+        assert!(events.contains(&"myapp:test".to_string()));
+    }
+
+    #[test]
+    fn test_clean_logs_keeps_eval_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let dotfile = dir.path();
+        let tasks_dir = dotfile.join("state").join("tasks");
+        fs::create_dir_all(tasks_dir.join("logs")).unwrap();
+        fs::write(dotfile.join("processes.log"), "12345").unwrap();
+        fs::write(tasks_dir.join("logs").join("myapp:build.log"), "123").unwrap();
+        fs::write(tasks_dir.join("myapp:build.json"), "{}").unwrap();
+        fs::write(dotfile.join("nix-eval-cache.db"), "cache").unwrap();
+
+        let freed = clean_state(dotfile, &[StateCategory::Logs]).unwrap();
+        assert_eq!(freed, vec![(StateCategory::Logs, 8)]);
+        assert!(!dotfile.join("processes.log").exists());
+        assert!(!tasks_dir.join("logs").exists());
+        assert!(tasks_dir.join("myapp:build.json").exists());
+        assert!(dotfile.join("nix-eval-cache.db").exists());
+
+        let freed = clean_state(dotfile, &StateCategory::ALL).unwrap();
+        assert_eq!(
+            freed,
+            vec![
+                (StateCategory::Logs, 0),
+                (StateCategory::EvalCache, 5),
+                (StateCategory::TaskState, 2)
+            ]
+        );
+        assert!(!dotfile.join("nix-eval-cache.db").exists());
+        assert!(!tasks_dir.join("myapp:build.json").exists());
+    }
+
+    #[test]
+    fn test_format_size() {
+        assert_eq!(format_size(512), "512 B");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(3 * 1024 * 1024), "3.0 MiB");
+    }
+
+    #[test]
+    fn test_write_file_if_changed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(DEVENV_FLAKE);
+
+        assert!(write_file_if_changed(&path, "{ }").unwrap());
+        let mtime = fs::metadata(&path).unwrap().modified().unwrap();
+
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        assert!(!write_file_if_changed(&path, "{ }").unwrap());
+        assert_eq!(fs::metadata(&path).unwrap().modified().unwrap(), mtime);
+
+        assert!(write_file_if_changed(&path, "{ x = 1; }").unwrap());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "{ x = 1; }");
+    }
+
+    #[test]
+    fn test_options_builder() {
+        let options = DevenvOptions::builder()
+            .devenv_root("/project")
+            .devenv_dotfile("/project/.devenv-ci")
+            .global_options({
+                let mut global_options = cli::GlobalOptions::default();
+                global_options.impure = true;
+                global_options
+            })
+            .build();
+        assert_eq!(options.devenv_root, Some(PathBuf::from("/project")));
+        assert_eq!(
+            options.devenv_dotfile,
+            Some(PathBuf::from("/project/.devenv-ci"))
+        );
+        assert!(options.global_options.unwrap().impure);
+        assert!(options.logger.is_none());
+    }
+
+    #[test]
+    fn test_processes_status() {
+        let dir = tempfile::tempdir().unwrap();
+        let pid_file = dir.path().join("processes.pid");
+
+        let status = processes_status(&pid_file);
+        assert_eq!(status.state, ProcessesState::NotStarted);
+        assert_eq!(status.pid, None);
+
+        fs::write(&pid_file, std::process::id().to_string()).unwrap();
+        let status = processes_status(&pid_file);
+        assert_eq!(status.state, ProcessesState::Running);
+        assert_eq!(status.pid, Some(std::process::id() as i32));
+        assert!(status.uptime_secs.is_some());
+
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        let exited_pid = child.id();
+        child.wait().unwrap();
+        fs::write(&pid_file, exited_pid.to_string()).unwrap();
+        let status = processes_status(&pid_file);
+        assert_eq!(status.state, ProcessesState::Stopped);
+        assert_eq!(status.pid, Some(exited_pid as i32));
+    }
+
+    #[test]
+    fn test_parse_process_list() {
+        let json = br#"[
+            {"name": "web", "namespace": "default", "status": "Running", "pid": 4242,
+             "age": 90000000000, "restarts": 2, "exit_code": 0, "is_ready": "Ready"},
+            {"name": "migrate", "namespace": "default", "status": "Completed", "pid": 0,
+             "age": 0, "restarts": 0, "exit_code": 0, "is_ready": "-"}
+        ]"#;
+        assert_eq!(
+            parse_process_list(json).unwrap(),
+            [
+                ProcessStatus {
+                    name: "web".to_string(),
+                    state: "Running".to_string(),
+                    pid: Some(4242),
+                    uptime_secs: Some(90),
+                    restarts: 2,
+                },
+                ProcessStatus {
+                    name: "migrate".to_string(),
+                    state: "Completed".to_string(),
+                    pid: None,
+                    uptime_secs: None,
+                    restarts: 0,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_port_conflicts() {
+        let processes = serde_json::json!({
+            "api": { "exec": "api", "ports": [8080, 9090] },
+            "web": {
+                "exec": "web",
+                "ports": [3000],
+                "process-compose": { "readiness_probe": { "http_get": { "port": 8080 } } }
+            },
+            "worker": {
+                "exec": "worker",
+                "ports": [9091],
+                "process-compose": { "liveness_probe": { "http_get": { "port": 9091 } } }
+            }
+        });
+        assert_eq!(
+            port_conflicts(&processes),
+            BTreeMap::from([(8080, vec!["api".to_string(), "web".to_string()])])
+        );
+
+        let processes = serde_json::json!({
+            "api": { "exec": "api", "ports": [8080] },
+            "web": { "exec": "web", "ports": [] }
+        });
+        assert!(port_conflicts(&processes).is_empty());
+    }
+
+    #[test]
+    fn test_store_path_matches() {
+        let path = Path::new("/nix/store/0m7gbkhk7q9zss3i2mw0hnfq0myc2fxb-openssl-3.0.13");
+        assert!(store_path_matches(path, "openssl"));
+        assert!(store_path_matches(path, "openssl-3.0.13"));
+        assert!(!store_path_matches(path, "open"));
+        assert!(!store_path_matches(
+            Path::new("/nix/store/0m7gbkhk7q9zss3i2mw0hnfq0myc2fxb-openssl-dev-3.0.13"),
+            "openssl"
+        ));
+    }
+
+    #[test]
+    fn test_default_output() {
+        let paths = |names: &[&str]| {
+            names
+                .iter()
+                .map(|name| {
+                    PathBuf::from(format!(
+                        "/nix/store/0m7gbkhk7q9zss3i2mw0hnfq0myc2fxb-{name}"
+                    ))
+                })
+                .collect::<Vec<_>>()
+        };
+        let outputs = paths(&["openssl-3.0.13-bin", "openssl-3.0.13", "openssl-3.0.13-dev"]);
+        assert_eq!(default_output(&outputs), Some(&outputs[1]));
+        let versions = paths(&["openssl-3.0.13", "openssl-1.1.1w"]);
+        assert_eq!(default_output(&versions), None);
+        let without_default = paths(&["openssl-3.0.13-bin", "openssl-3.0.13-dev"]);
+        assert_eq!(default_output(&without_default), None);
+    }
+
+    #[test]
+    fn test_systemd_env_file() {
+        let vars = BTreeMap::from([
+            ("DISCOUNT".to_string(), "50%".to_string()),
+            ("GREETING".to_string(), r#"say "hi" \o/"#.to_string()),
+        ]);
+        assert_eq!(
+            systemd_env_file(&vars).unwrap(),
+            "DISCOUNT=50%\nGREETING=say \\\"hi\\\" \\\\o/\n"
+        );
+
+        let vars = BTreeMap::from([("MOTD".to_string(), "line 1\nline 2".to_string())]);
+        let err = systemd_env_file(&vars).unwrap_err();
+        assert!(err.to_string().contains("MOTD has a multi-line value"));
+    }
+
+    #[test]
+    fn test_clean_keep() {
+        let keep = ["SSH_AUTH_SOCK".to_string()];
+        let config_clean = config::Clean {
+            enabled: true,
+            keep: vec!["EDITOR".to_string()],
+        };
+        assert_eq!(
+            clean_keep(None, Some(&config_clean), &keep),
+            Some(vec!["EDITOR".to_string(), "SSH_AUTH_SOCK".to_string()])
+        );
+        assert_eq!(
+            clean_keep(Some(&[]), Some(&config_clean), &keep[..]),
+            Some(vec!["SSH_AUTH_SOCK".to_string()])
+        );
+        assert_eq!(
+            clean_keep(Some(&keep[..]), None, &keep),
+            Some(vec!["SSH_AUTH_SOCK".to_string()])
+        );
+
+        // Not a clean shell, so everything is let through anyway
+        let disabled = config::Clean {
+            enabled: false,
+            keep: vec![],
+        };
+        assert_eq!(clean_keep(None, Some(&disabled), &keep), None);
+        assert_eq!(clean_keep(None, None, &keep), None);
+    }
+
+    #[test]
+    fn test_nix_config_in_flake() {
+        let settings = HashMap::from([
+            (
+                "extra-substituters".to_string(),
+                "https://example.cachix.org".to_string(),
+            ),
+            ("bash-prompt".to_string(), "\"${PWD}\" $ ".to_string()),
+        ]);
+        let flake = FLAKE_TMPL.replace("__DEVENV_NIX_CONFIG__", &nix_config_nix(&settings));
+        assert!(flake.contains(
+            r#"nixConfig = { "bash-prompt" = "\"\${PWD}\" $ "; "extra-substituters" = "https://example.cachix.org"; };"#
+        ));
+        assert!(FLAKE_TMPL
+            .replace("__DEVENV_NIX_CONFIG__", &nix_config_nix(&HashMap::new()))
+            .contains("nixConfig = { };"));
+    }
+
+    #[test]
+    fn test_deserialize_eval() {
+        let enabled: bool = deserialize_eval("languages.python.enable", "true").unwrap();
+        assert!(enabled);
+
+        let error = deserialize_eval::<Vec<String>>("languages.python.enable", "true").unwrap_err();
+        assert!(matches!(
+            &error,
+            EvalError::Deserialize { attribute, .. } if attribute == "languages.python.enable"
+        ));
+        assert_eq!(
+            error.to_string(),
+            "Failed to deserialize languages.python.enable"
+        );
+    }
+
+    #[test]
+    fn test_pinned_packages_nix() {
+        assert_eq!(pinned_packages_nix(&[]), "{ }");
+        assert_eq!(
+            pinned_packages_nix(&[
+                ("hello".to_string(), "/nix/store/abc-hello-2.12".into()),
+                ("jq".to_string(), "/nix/store/def-jq-1.7".into()),
+            ]),
+            r#"{ "hello" = "/nix/store/abc-hello-2.12"; "jq" = "/nix/store/def-jq-1.7"; }"#
+        );
+        assert_eq!(
+            pinned_packages_nix(&[("hello".to_string(), "/nix/store/a\"${x}".into())]),
+            r#"{ "hello" = "/nix/store/a\"\${x}"; }"#
+        );
+    }
+
+    #[test]
+    fn test_group_by_namespace() {
+        let option = |name: &str| DevenvOptionResult {
+            name: name.to_string(),
+            type_: "boolean".to_string(),
+            default: "false".to_string(),
+            description: String::new(),
+        };
+        let results = vec![
+            option("services.postgres.enable"),
+            option("languages.python.enable"),
+            option("languages.python.poetry.enable"),
+        ];
+
+        let grouped = group_by_namespace(results, |r| &r.name);
+        let names = |value: &serde_json::Value, namespace: &str| {
+            value[namespace]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|r| r["name"].as_str().unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+        let value = serde_json::to_value(&grouped).unwrap();
+
+        assert_eq!(value.as_object().unwrap().len(), 2);
+        assert_eq!(
+            names(&value, "languages"),
+            vec!["languages.python.enable", "languages.python.poetry.enable"]
+        );
+        assert_eq!(names(&value, "services"), vec!["services.postgres.enable"]);
+        assert_eq!(value["services"][0]["type"], "boolean");
+    }
+}
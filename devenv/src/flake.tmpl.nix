@@ -1,4 +1,6 @@
 {
+  nixConfig = __DEVENV_NIX_CONFIG__;
+
   inputs =
     let
       __DEVENV_VARS__
@@ -26,7 +28,9 @@
                 in
                   input.overlays.${overlay} or (throw "Input `${inputName}` has no overlay called `${overlay}`. Supported overlays: ${nixpkgs.lib.concatStringsSep ", " (builtins.attrNames input.overlays)}"))
               inputAttrs.overlays or [ ];
-          overlays = nixpkgs.lib.flatten (nixpkgs.lib.mapAttrsToList getOverlays (devenv.inputs or { }));
+          # Packages replaced via `devenv --pin-package NAME=STORE_PATH`
+          pinOverlay = final: prev: builtins.mapAttrs (name: path: builtins.storePath path) pinned_packages;
+          overlays = nixpkgs.lib.flatten (nixpkgs.lib.mapAttrsToList getOverlays (devenv.inputs or { })) ++ [ pinOverlay ];
           pkgs = import nixpkgs {
             inherit system;
             config = {
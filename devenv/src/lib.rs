@@ -4,6 +4,10 @@ pub mod config;
 mod devenv;
 pub mod log;
 
-pub use cli::{default_system, GlobalOptions};
-pub use devenv::{Devenv, DevenvOptions};
+pub use cli::{default_system, parse_system, GlobalOptions, SYSTEMS};
+pub use cnix::{resolve_nix, NixError, NixErrorKind, NixErrorLocation};
+pub use devenv::{
+    clean, BuildError, Devenv, DevenvOptions, DevenvOptionsBuilder, EvalError, StateCategory,
+    TaskRunReport, TasksRunOptions, NO_BANNER_ENV,
+};
 pub use devenv_tasks as tasks;
@@ -1,10 +1,35 @@
 use console::style;
 use std::io::Write;
-use std::time::Instant;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// What devenv reports while it runs, for programs embedding it.
+#[derive(Clone, Debug)]
+pub enum Event {
+    /// A log record, including errors.
+    Log { level: Level, message: String },
+    /// A step such as evaluating or building started.
+    Started { step: String },
+    /// A step started with [`Event::Started`] finished.
+    Finished {
+        step: String,
+        duration: Duration,
+        failed: bool,
+    },
+    /// A task started or completed.
+    Task(devenv_tasks::TaskReport),
+}
+
+/// Receives [`Event`]s instead of them being written to stderr.
+pub type EventSink = Arc<dyn Fn(Event) + Send + Sync>;
 
 pub enum LogProgressCreator {
     Silent,
     Logging,
+    /// Report progress as tracing events, for `--log-format json`.
+    Tracing,
+    /// Report progress to an [`EventSink`].
+    Sink(EventSink),
 }
 
 impl LogProgressCreator {
@@ -13,6 +38,8 @@ impl LogProgressCreator {
         match self {
             Silent => None,
             Logging => Some(LogProgress::new(message, true)),
+            Tracing => Some(LogProgress::tracing(message)),
+            Sink(sink) => Some(LogProgress::sink(message, sink.clone())),
         }
     }
 
@@ -21,6 +48,8 @@ impl LogProgressCreator {
         match self {
             Silent => None,
             Logging => Some(LogProgress::new(message, false)),
+            Tracing => Some(LogProgress::tracing(message)),
+            Sink(sink) => Some(LogProgress::sink(message, sink.clone())),
         }
     }
 }
@@ -28,6 +57,8 @@ impl LogProgressCreator {
 pub struct LogProgress {
     message: String,
     start: Option<Instant>,
+    tracing: bool,
+    sink: Option<EventSink>,
     pub failed: bool,
 }
 
@@ -42,6 +73,32 @@ impl LogProgress {
         LogProgress {
             message: message.to_string(),
             start: Some(Instant::now()),
+            tracing: false,
+            sink: None,
+            failed: false,
+        }
+    }
+
+    pub fn tracing(message: &str) -> LogProgress {
+        tracing::info!(progress = message, "{message} ...");
+        LogProgress {
+            message: message.to_string(),
+            start: Some(Instant::now()),
+            tracing: true,
+            sink: None,
+            failed: false,
+        }
+    }
+
+    pub fn sink(message: &str, sink: EventSink) -> LogProgress {
+        sink(Event::Started {
+            step: message.to_string(),
+        });
+        LogProgress {
+            message: message.to_string(),
+            start: Some(Instant::now()),
+            tracing: false,
+            sink: Some(sink),
             failed: false,
         }
     }
@@ -50,6 +107,33 @@ impl LogProgress {
 impl Drop for LogProgress {
     fn drop(&mut self) {
         let duration = self.start.unwrap_or_else(Instant::now).elapsed();
+        if let Some(sink) = &self.sink {
+            sink(Event::Finished {
+                step: self.message.clone(),
+                duration,
+                failed: self.failed,
+            });
+            return;
+        }
+        if self.tracing {
+            let secs = duration.as_secs_f32();
+            if self.failed {
+                tracing::error!(
+                    progress = self.message.as_str(),
+                    duration_secs = secs,
+                    "{} failed",
+                    self.message
+                );
+            } else {
+                tracing::info!(
+                    progress = self.message.as_str(),
+                    duration_secs = secs,
+                    "{} done",
+                    self.message
+                );
+            }
+            return;
+        }
         let prefix = if self.failed {
             style("✖").red()
         } else {
@@ -64,7 +148,7 @@ impl Drop for LogProgress {
     }
 }
 
-#[derive(PartialEq, Eq, PartialOrd, Ord, Clone)]
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Debug)]
 pub enum Level {
     Silent,
     Error,
@@ -73,14 +157,47 @@ pub enum Level {
     Debug,
 }
 
+/// How log records are written to stderr.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human-friendly output.
+    #[default]
+    Human,
+    /// One JSON object per line, with the level, target, timestamp and enclosing spans.
+    Json,
+}
+
 #[derive(Clone)]
 pub struct Logger {
     pub level: Level,
+    pub format: LogFormat,
+    sink: Option<EventSink>,
 }
 
 impl Logger {
     pub fn new(level: Level) -> Logger {
-        Logger { level }
+        Logger::with_format(level, LogFormat::Human)
+    }
+
+    pub fn with_format(level: Level, format: LogFormat) -> Logger {
+        Logger {
+            level,
+            format,
+            sink: None,
+        }
+    }
+
+    /// Send records to `sink` as [`Event::Log`] instead of writing them to stderr.
+    pub fn with_sink(self, sink: EventSink) -> Logger {
+        Logger {
+            sink: Some(sink),
+            ..self
+        }
+    }
+
+    /// Where records are sent instead of stderr, if anywhere.
+    pub fn sink(&self) -> Option<&EventSink> {
+        self.sink.as_ref()
     }
 
     pub fn info(&self, message: &str) {
@@ -103,24 +220,158 @@ impl Logger {
         if level > self.level {
             return;
         }
-        match level {
-            Level::Info => {
-                let prefix = style("•").blue();
-                eprintln!("{} {}", prefix, message);
-            }
-            Level::Error => {
-                let prefix = style("✖").red();
-                eprintln!("{} {}", prefix, message);
-            }
-            Level::Warn => {
-                let prefix = style("•").yellow();
-                eprintln!("{} {}", prefix, message);
-            }
-            Level::Debug => {
-                let prefix = style("•").italic();
-                eprintln!("{} {}", prefix, message);
+        if let Some(sink) = &self.sink {
+            sink(Event::Log {
+                level,
+                message: message.to_string(),
+            });
+            return;
+        }
+        if self.format == LogFormat::Json {
+            match level {
+                Level::Info => tracing::info!("{message}"),
+                Level::Error => tracing::error!("{message}"),
+                Level::Warn => tracing::warn!("{message}"),
+                Level::Debug => tracing::debug!("{message}"),
+                Level::Silent => {}
             }
-            Level::Silent => {}
+            return;
+        }
+        if let Some(line) = human_line(message, &level) {
+            eprintln!("{line}");
+        }
+    }
+}
+
+/// Format a log record for [`LogFormat::Human`].
+fn human_line(message: &str, level: &Level) -> Option<String> {
+    let prefix = match level {
+        Level::Info => style("•").blue(),
+        Level::Error => style("✖").red(),
+        Level::Warn => style("•").yellow(),
+        Level::Debug => style("•").italic(),
+        Level::Silent => return None,
+    };
+    Some(format!("{} {}", prefix, message))
+}
+
+/// Install a global tracing subscriber writing JSON records to stderr.
+///
+/// Only needed for [`LogFormat::Json`]; the default output is written by [`Logger`] directly.
+pub fn init_json_tracing(level: &Level) {
+    tracing::subscriber::set_global_default(json_subscriber(level, std::io::stderr))
+        .expect("Failed to set the tracing subscriber");
+}
+
+/// A subscriber writing one JSON object per record to `writer`,
+/// with the timestamp, level, target, fields and the spans the record was emitted in.
+fn json_subscriber<W>(level: &Level, writer: W) -> impl tracing::Subscriber + Send + Sync
+where
+    W: for<'w> tracing_subscriber::fmt::MakeWriter<'w> + Send + Sync + 'static,
+{
+    let max_level = match level {
+        Level::Silent => tracing::level_filters::LevelFilter::OFF,
+        Level::Error => tracing::level_filters::LevelFilter::ERROR,
+        Level::Warn => tracing::level_filters::LevelFilter::WARN,
+        Level::Info => tracing::level_filters::LevelFilter::INFO,
+        Level::Debug => tracing::level_filters::LevelFilter::DEBUG,
+    };
+    tracing_subscriber::fmt()
+        .json()
+        .with_max_level(max_level)
+        .with_current_span(true)
+        .with_span_list(true)
+        .with_writer(writer)
+        .finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn json_records_have_fields_and_spans() {
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let subscriber = json_subscriber(&Level::Info, move || writer.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            let logger = Logger::with_format(Level::Info, LogFormat::Json);
+            let _span = tracing::info_span!("shell", cmd = "ls").entered();
+            logger.info("Entering shell");
+            logger.debug("filtered out");
+        });
+
+        let output = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        let records = output
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(records.len(), 1);
+        let record = &records[0];
+        assert_eq!(record["level"], "INFO");
+        assert_eq!(record["fields"]["message"], "Entering shell");
+        assert_eq!(record["span"]["cmd"], "ls");
+        assert_eq!(record["spans"][0]["name"], "shell");
+        assert!(record["target"].is_string());
+        assert!(record["timestamp"].is_string());
+    }
+
+    #[test]
+    fn sink_receives_logs_and_progress() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let sink: EventSink = {
+            let events = events.clone();
+            Arc::new(move |event| events.lock().unwrap().push(event))
+        };
+        let logger = Logger::new(Level::Info).with_sink(sink.clone());
+        {
+            let mut progress = LogProgressCreator::Sink(sink)
+                .with_newline("Building shell")
+                .unwrap();
+            logger.error("Evaluation failed");
+            logger.debug("filtered out");
+            progress.failed = true;
         }
+
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 3);
+        assert!(matches!(&events[0], Event::Started { step } if step == "Building shell"));
+        assert!(matches!(
+            &events[1],
+            Event::Log { level: Level::Error, message } if message == "Evaluation failed"
+        ));
+        assert!(matches!(
+            &events[2],
+            Event::Finished { step, failed: true, .. } if step == "Building shell"
+        ));
+    }
+
+    #[test]
+    fn human_lines() {
+        console::set_colors_enabled_stderr(false);
+        console::set_colors_enabled(false);
+        assert_eq!(
+            human_line("Entering shell", &Level::Info).as_deref(),
+            Some("• Entering shell")
+        );
+        assert_eq!(
+            human_line("Tests failed", &Level::Error).as_deref(),
+            Some("✖ Tests failed")
+        );
+        assert_eq!(human_line("quiet", &Level::Silent), None);
     }
 }
@@ -1,23 +1,31 @@
 use clap::crate_version;
 use devenv::{
-    cli::{Cli, Commands, ContainerCommand, InputsCommand, ProcessesCommand, TasksCommand},
-    config, log, Devenv,
+    cli::{
+        CacheCommand, CheckFormat, Cli, Commands, ConfigCommand, ContainerCommand, InputsCommand,
+        ProcessesCommand, TasksCommand,
+    },
+    config, log, Devenv, StateCategory, TasksRunOptions,
 };
 use miette::Result;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let cli = Cli::parse_and_resolve_options();
+    let mut cli = Cli::parse_and_resolve_options();
 
     if let Commands::Version { .. } = cli.command {
         println!(
             "devenv {} ({})",
             crate_version!(),
-            cli.global_options.system
+            cli.global_options.system()
         );
         return Ok(());
     }
 
+    if let Commands::Completion { shell } = cli.command {
+        shell.generate(&mut std::io::stdout());
+        return Ok(());
+    }
+
     let level = if cli.global_options.verbose {
         log::Level::Debug
     } else if cli.global_options.quiet {
@@ -26,19 +34,68 @@ async fn main() -> Result<()> {
         log::Level::Info
     };
 
-    let logger = log::Logger::new(level);
+    if cli.global_options.log_format == log::LogFormat::Json {
+        log::init_json_tracing(&level);
+    }
+    let logger = log::Logger::with_format(level, cli.global_options.log_format);
+
+    if let Commands::Config {
+        command: ConfigCommand::Check { format },
+    } = cli.command
+    {
+        let problems = config::check("./");
+        for problem in &problems {
+            match format {
+                CheckFormat::Human => logger.error(&problem.to_string()),
+                CheckFormat::Github => println!("{}", problem.github_annotation()),
+            }
+        }
+        if !problems.is_empty() {
+            miette::bail!("Found {} problems in the configuration", problems.len());
+        }
+        logger.info("Configuration is valid.");
+        return Ok(());
+    }
+
+    // Handled before the evaluation cache is opened, as it may be removed
+    if let Commands::Clean {
+        logs,
+        eval_cache,
+        task_state,
+        all,
+    } = cli.command
+    {
+        let categories: Vec<StateCategory> = if all {
+            StateCategory::ALL.to_vec()
+        } else {
+            [
+                (logs, StateCategory::Logs),
+                (eval_cache, StateCategory::EvalCache),
+                (task_state, StateCategory::TaskState),
+            ]
+            .into_iter()
+            .filter_map(|(selected, category)| selected.then_some(category))
+            .collect()
+        };
+        let pwd = std::env::current_dir().expect("Failed to get current directory");
+        return devenv::clean(&logger, &pwd.join(".devenv"), &categories);
+    }
 
     let mut config = config::Config::load()?;
     for input in cli.global_options.override_input.chunks_exact(2) {
         config.add_input(&input[0].clone(), &input[1].clone(), &[]);
     }
+    if runs_nix(&cli.command) {
+        cli.global_options.nix = Some(devenv::resolve_nix(&cli.global_options, &config)?);
+    }
+    cli.global_options
+        .resolve_system(config.system.as_deref())
+        .map_err(|e| miette::miette!(e))?;
 
-    let mut options = devenv::DevenvOptions {
-        logger: Some(logger.clone()),
-        global_options: Some(cli.global_options),
-        config,
-        ..Default::default()
-    };
+    let mut options = devenv::DevenvOptions::builder()
+        .logger(logger.clone())
+        .global_options(cli.global_options)
+        .config(config);
 
     // we let Drop delete the dir after all commands have ran
     let _tmpdir = if let Commands::Test {
@@ -53,17 +110,27 @@ async fn main() -> Result<()> {
                 "Overriding .devenv to {}",
                 tmpdir.path().file_name().unwrap().to_str().unwrap()
             ));
-            options.devenv_dotfile = Some(tmpdir.path().to_path_buf());
+            options = options.devenv_dotfile(tmpdir.path());
         }
         Some(tmpdir)
     } else {
         None
     };
 
-    let mut devenv = Devenv::new(options).await;
+    let mut devenv = Devenv::new(options.build()).await?;
 
     match cli.command {
-        Commands::Shell { cmd, args } => devenv.shell(&cmd, &args, true).await,
+        Commands::Shell {
+            cmd,
+            args,
+            no_banner,
+            keep,
+        } => {
+            if no_banner {
+                std::env::set_var(devenv::NO_BANNER_ENV, "1");
+            }
+            devenv.shell(&cmd, &args, &keep, true).await
+        }
         Commands::Test { .. } => devenv.test().await,
         Commands::Container {
             registry,
@@ -125,9 +192,17 @@ async fn main() -> Result<()> {
             Ok(())
         }
         Commands::Init { target } => devenv.init(&target),
-        Commands::Search { name } => devenv.search(&name).await,
+        Commands::Search { name, json } => devenv.search(&name, json).await,
         Commands::Gc {} => devenv.gc(),
-        Commands::Info {} => devenv.info().await,
+        Commands::Cache { command } => match command {
+            CacheCommand::Export { file } => devenv.cache_export(&file).await,
+            CacheCommand::Import { file } => devenv.cache_import(&file).await,
+        },
+        Commands::Info {
+            check_updates: false,
+            ..
+        } => devenv.info().await,
+        Commands::Info { json, .. } => devenv.check_updates(json).await,
         Commands::Repl {} => devenv.repl(),
         Commands::Build { attributes } => devenv.build(&attributes).await,
         Commands::Update { name } => devenv.update(&name).await,
@@ -137,21 +212,78 @@ async fn main() -> Result<()> {
                 devenv.up(process.as_deref(), &detach, &detach).await
             }
             ProcessesCommand::Down {} => devenv.down(),
+            ProcessesCommand::Status { json } => devenv.processes_status(json).await,
         },
         Commands::Tasks { command } => match command {
-            TasksCommand::Run { tasks } => devenv.tasks_run(tasks).await,
+            TasksCommand::Run {
+                tasks,
+                env,
+                run_timeout,
+                only_deps,
+                skip,
+                max_concurrency,
+                json,
+                force,
+                max_log_lines,
+                timings,
+                stream,
+                no_prefix,
+                dry_run,
+            } => {
+                let options = TasksRunOptions {
+                    env,
+                    run_timeout,
+                    only_deps,
+                    skip,
+                    max_concurrency,
+                    json,
+                    force,
+                    max_log_lines,
+                    timings,
+                    stream,
+                    no_prefix,
+                    dry_run,
+                };
+                devenv.tasks_run(tasks, options).await
+            }
         },
         Commands::Inputs { command } => match command {
             InputsCommand::Add { name, url, follows } => devenv.inputs_add(&name, &url, &follows),
+            InputsCommand::List { json } => devenv.inputs_list(json).await,
         },
 
         // hidden
         Commands::Assemble => devenv.assemble(false),
         Commands::PrintDevEnv { json } => devenv.print_dev_env(json).await,
+        Commands::Env { format } => devenv.env(format).await,
+        Commands::WhyDepends { package, json } => devenv.why_depends(&package, json).await,
         Commands::GenerateJSONSchema => {
             config::write_json_schema();
             Ok(())
         }
-        Commands::Version {} => unreachable!(),
+        Commands::Version {}
+        | Commands::Completion { .. }
+        | Commands::Config { .. }
+        | Commands::Clean { .. } => {
+            unreachable!()
+        }
     }
 }
+
+/// Whether the command needs a Nix installation, which is then checked before running it.
+///
+/// `processes status` only needs Nix to list the processes that are running.
+fn runs_nix(command: &Commands) -> bool {
+    !matches!(
+        command,
+        Commands::Init { .. }
+            | Commands::GenerateJSONSchema
+            | Commands::Cache { .. }
+            | Commands::Inputs {
+                command: InputsCommand::Add { .. }
+            }
+            | Commands::Processes {
+                command: ProcessesCommand::Status { .. }
+            }
+    )
+}
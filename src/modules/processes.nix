@@ -9,6 +9,17 @@ let
         description = "Bash code to run the process.";
       };
 
+      ports = lib.mkOption {
+        type = types.listOf types.port;
+        default = [ ];
+        description = ''
+          Ports the process listens on.
+
+          ``devenv up`` refuses to start when several processes declare the same port.
+        '';
+        example = [ 8080 ];
+      };
+
       process-compose = lib.mkOption {
         type = types.attrs; # TODO: type this explicitly?
         default = { };
@@ -61,9 +61,45 @@ let
               before = config.before;
               command = config.command;
               input = config.input;
+              assert_output_matches = config.assertOutputMatches;
+              timeout = config.timeout;
+              input_files = config.inputFiles;
+              input_env = config.inputEnv;
+              output_files = config.outputFiles;
+              max_output_lines = config.maxOutputLines;
             };
             description = "Internal configuration for the task.";
           };
+          assertOutputMatches = lib.mkOption {
+            type = types.nullOr types.str;
+            default = null;
+            description = "Fail the task unless its combined stdout and stderr match this regular expression.";
+          };
+          timeout = lib.mkOption {
+            type = types.nullOr types.ints.positive;
+            default = null;
+            description = "Terminate the task if its command runs for longer than this many seconds.";
+          };
+          inputFiles = lib.mkOption {
+            type = types.listOf types.str;
+            default = [ ];
+            description = "Globs of files whose contents decide whether the task needs to run again.";
+          };
+          inputEnv = lib.mkOption {
+            type = types.listOf types.str;
+            default = [ ];
+            description = "Environment variables whose values decide whether the task needs to run again.";
+          };
+          outputFiles = lib.mkOption {
+            type = types.listOf types.str;
+            default = [ ];
+            description = "Files the task produces. The task runs again if any of them is missing.";
+          };
+          maxOutputLines = lib.mkOption {
+            type = types.nullOr types.ints.positive;
+            default = null;
+            description = "Show only this many of the last lines of the task's output when it fails. Overrides `devenv tasks run --max-log-lines`.";
+          };
           exports = lib.mkOption {
             type = types.listOf types.str;
             default = [ ];
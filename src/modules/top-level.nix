@@ -279,8 +279,8 @@ in
         export DIRENV_ACTIVE="$PWD:''${DIRENV_ACTIVE-}"
       fi
 
-      # devenv helper
-      if [ ! type -p direnv &>/dev/null && -f .envrc ]; then
+      # devenv helper, skipped with `devenv shell --no-banner`
+      if [ -z "''${DEVENV_NO_BANNER-}" ] && [ ! type -p direnv &>/dev/null && -f .envrc ]; then
         echo "You have .envrc but direnv command is not installed."
         echo "Please install direnv: https://direnv.net/docs/installation.html"
       fi
@@ -43,6 +43,14 @@ in
   };
 
   config = lib.mkIf cfg.warnOnNewVersion {
-    enterShell = action."${ toString (builtins.compareVersions cfg.cliVersion cfg.latestVersion) }";
+    enterShell =
+      let
+        message = action."${ toString (builtins.compareVersions cfg.cliVersion cfg.latestVersion) }";
+      in
+      lib.optionalString (message != "") ''
+        if [ -z "''${DEVENV_NO_BANNER-}" ]; then
+          ${message}
+        fi
+      '';
   };
 }
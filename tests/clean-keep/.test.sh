@@ -0,0 +1,6 @@
+set -xe
+
+export SSH_AUTH_SOCK=/tmp/agent.sock
+export LEAKY_VAR=leak
+devenv --clean shell --keep SSH_AUTH_SOCK 'echo "sock=${SSH_AUTH_SOCK-} leaky=${LEAKY_VAR-}"' >output.txt
+grep -x "sock=/tmp/agent.sock leaky=" output.txt
@@ -0,0 +1,3 @@
+{
+  env.GREETING = "hello";
+}
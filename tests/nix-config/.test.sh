@@ -0,0 +1,3 @@
+set -xe
+
+grep -F '"bash-prompt-prefix" = "(nix-config) ";' .devenv.flake.nix
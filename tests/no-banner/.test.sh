@@ -0,0 +1,5 @@
+set -xe
+
+touch .envrc
+[ "$(devenv shell --no-banner echo hello)" = "hello" ]
+[ "$(DEVENV_NO_BANNER=1 devenv shell echo hello)" = "hello" ]
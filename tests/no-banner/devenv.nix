@@ -0,0 +1,4 @@
+{
+  devenv.warnOnNewVersion = true;
+  devenv.latestVersion = "0.0.1";
+}
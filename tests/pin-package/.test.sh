@@ -0,0 +1,5 @@
+set -xe
+
+pinned=$(devenv build outputs.pinned-hello)
+devenv shell --pin-package "hello=$pinned" hello | grep "pinned hello"
+devenv shell --pin-package "hello=$pinned" -- bash -c 'echo $PATH' | grep "$pinned/bin"
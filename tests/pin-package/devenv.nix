@@ -0,0 +1,4 @@
+{ pkgs, ... }: {
+  packages = [ pkgs.hello ];
+  outputs.pinned-hello = pkgs.writeShellScriptBin "hello" "echo pinned hello";
+}
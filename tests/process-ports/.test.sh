@@ -0,0 +1,6 @@
+set -xe
+
+devenv up 2> error.log && exit 1
+grep "Port 8080 is declared by several processes: first, second" error.log
+# nothing was started
+[ ! -e started-first ] && [ ! -e started-second ]
@@ -0,0 +1,10 @@
+{
+  processes.first = {
+    exec = "touch started-first; sleep 60";
+    ports = [ 8080 ];
+  };
+  processes.second = {
+    exec = "touch started-second; sleep 60";
+    ports = [ 8080 ];
+  };
+}